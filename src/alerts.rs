@@ -0,0 +1,347 @@
+// alert rules that are checked against the clients, e.g. a low battery or a device that is gone
+use crate::client_state::{ClientManager, ClientStateChange};
+use crate::webhooks::{Event, Webhooks};
+use chrono::{Local, NaiveTime};
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tracing::{info, warn};
+
+// rules are checked at least this often, some conditions depend on the time
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct Rule {
+    pub name: String,
+    #[serde(flatten)]
+    pub condition: Condition,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Condition {
+    // battery level in percent while not charging
+    BatteryBelow {
+        percent: f64,
+    },
+    // a device that was connected is gone for this long
+    DisconnectedFor {
+        secs: u64,
+    },
+    // audio in is unmuted outside of the window, which may span midnight
+    UnmutedOutside {
+        #[serde(deserialize_with = "deserialize_time")]
+        from: NaiveTime,
+        #[serde(deserialize_with = "deserialize_time")]
+        to: NaiveTime,
+    },
+}
+
+#[derive(Deserialize)]
+struct RulesFile {
+    rules: Vec<Rule>,
+}
+
+// "HH:MM" in local time
+fn deserialize_time<'de, D: Deserializer<'de>>(deserializer: D) -> Result<NaiveTime, D::Error> {
+    let time = String::deserialize(deserializer)?;
+    NaiveTime::parse_from_str(&time, "%H:%M").map_err(serde::de::Error::custom)
+}
+
+// no rules if the file does not exist
+pub fn load_rules(path: &Path) -> Result<Vec<Rule>, String> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => Err(format!("{}: {}", path.display(), e))?,
+    };
+    match serde_json::from_reader::<_, RulesFile>(BufReader::new(file)) {
+        Ok(rules_file) => Ok(rules_file.rules),
+        Err(e) => Err(format!("{}: {}", path.display(), e)),
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Alert {
+    pub rule: String,
+    pub client_name: String,
+    pub message: String,
+    pub since: SystemTime,
+    pub acknowledged: bool,
+    pub snoozed_until: Option<SystemTime>,
+}
+
+impl Alert {
+    pub fn is_snoozed(&self) -> bool {
+        self.snoozed_until
+            .is_some_and(|snoozed_until| SystemTime::now() < snoozed_until)
+    }
+}
+
+// alerts whose condition still holds, an alert is gone once its condition is resolved
+#[derive(Clone, Default)]
+pub struct Alerts {
+    alerts: Arc<RwLock<Vec<Alert>>>,
+}
+
+impl Alerts {
+    pub fn get_alerts(&self) -> Vec<Alert> {
+        self.alerts.read().unwrap().clone()
+    }
+
+    pub fn acknowledge(&self, rule: &str, client_name: &str) {
+        let mut alerts = self.alerts.write().unwrap();
+        if let Some(alert) = alerts
+            .iter_mut()
+            .find(|alert| alert.rule == rule && alert.client_name == client_name)
+        {
+            alert.acknowledged = true;
+            info!(rule, client_name, "Alert acknowledged");
+        }
+    }
+
+    pub fn snooze(&self, rule: &str, client_name: &str, duration: Duration) {
+        let mut alerts = self.alerts.write().unwrap();
+        if let Some(alert) = alerts
+            .iter_mut()
+            .find(|alert| alert.rule == rule && alert.client_name == client_name)
+        {
+            alert.snoozed_until = Some(SystemTime::now() + duration);
+            info!(
+                rule,
+                client_name,
+                minutes = duration.as_secs() / 60,
+                "Alert snoozed"
+            );
+        }
+    }
+
+    // takes the conditions that hold now by rule and client name, returns the new alerts
+    fn update(&self, active: HashMap<(String, String), String>) -> Vec<Alert> {
+        let mut raised = Vec::new();
+        let mut alerts = self.alerts.write().unwrap();
+        alerts.retain(|alert| {
+            let key = (alert.rule.clone(), alert.client_name.clone());
+            let is_active = active.contains_key(&key);
+            if !is_active {
+                info!(rule = %alert.rule, client_name = %alert.client_name, "Alert resolved");
+            }
+            is_active
+        });
+        for ((rule, client_name), message) in active {
+            match alerts
+                .iter_mut()
+                .find(|alert| alert.rule == rule && alert.client_name == client_name)
+            {
+                // e.g. the battery level went down further
+                Some(alert) => alert.message = message,
+                None => {
+                    warn!(rule = %rule, client_name = %client_name, "{}", message);
+                    let alert = Alert {
+                        rule,
+                        client_name,
+                        message,
+                        since: SystemTime::now(),
+                        acknowledged: false,
+                        snoozed_until: None,
+                    };
+                    raised.push(alert.clone());
+                    alerts.push(alert);
+                }
+            }
+        }
+        alerts.sort_by_key(|alert| alert.since);
+        raised
+    }
+}
+
+// whether now is inside the window from - to, which spans midnight if it ends before it starts
+fn is_allowed(from: NaiveTime, to: NaiveTime, now: NaiveTime) -> bool {
+    if from <= to {
+        from <= now && now < to
+    } else {
+        from <= now || now < to
+    }
+}
+
+// checks the rules whenever a client changes and every second
+pub fn run(rules: Vec<Rule>, alerts: Alerts, client_manager: ClientManager, webhooks: Webhooks) {
+    let change_receiver = client_manager.get_change_receiver();
+    // devices that were connected since the server started and are gone now
+    let mut disconnected: HashMap<String, SystemTime> = HashMap::new();
+
+    loop {
+        match change_receiver.recv_timeout(CHECK_INTERVAL) {
+            Ok((_, ClientStateChange::Remove(state))) => {
+                if let Some(client_name) = state.client_name {
+                    disconnected.insert(client_name, SystemTime::now());
+                }
+            }
+            Ok(_) | Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+
+        let clients: Vec<_> = client_manager
+            .get_all_clients()
+            .into_iter()
+            .filter(|state| state.client_name.is_some())
+            .collect();
+        // a device that logged in again is back, even if it still has another session
+        disconnected.retain(|client_name, _| {
+            !clients
+                .iter()
+                .any(|state| state.client_name.as_ref() == Some(client_name))
+        });
+
+        let now = Local::now().time();
+        let mut active = HashMap::new();
+        for rule in rules.iter() {
+            match &rule.condition {
+                Condition::BatteryBelow { percent } => {
+                    for state in clients.iter() {
+                        let level = match state.battery_level {
+                            Some(level) if state.is_charging != Some(true) => level * 100.0,
+                            _ => continue,
+                        };
+                        if level < *percent {
+                            let client_name = state.client_name.clone().unwrap();
+                            let message = format!("Battery at {:.0}%", level);
+                            active.insert((rule.name.clone(), client_name), message);
+                        }
+                    }
+                }
+                Condition::DisconnectedFor { secs } => {
+                    for (client_name, since) in disconnected.iter() {
+                        let gone = since.elapsed().unwrap_or_default().as_secs();
+                        if gone >= *secs {
+                            let message = format!("Disconnected for {}s", gone);
+                            active.insert((rule.name.clone(), client_name.clone()), message);
+                        }
+                    }
+                }
+                Condition::UnmutedOutside { from, to } => {
+                    if is_allowed(*from, *to, now) {
+                        continue;
+                    }
+                    for state in clients.iter() {
+                        if state.send_mute == Some(false) {
+                            let client_name = state.client_name.clone().unwrap();
+                            let message = format!(
+                                "Audio in unmuted outside of {} - {}",
+                                from.format("%H:%M"),
+                                to.format("%H:%M")
+                            );
+                            active.insert((rule.name.clone(), client_name), message);
+                        }
+                    }
+                }
+            }
+        }
+        for alert in alerts.update(active) {
+            webhooks.notify(Event::Alert {
+                rule: alert.rule,
+                client_name: alert.client_name,
+                message: alert.message,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(hour: u32, min: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, min, 0).unwrap()
+    }
+
+    fn active(keys: &[(&str, &str, &str)]) -> HashMap<(String, String), String> {
+        keys.iter()
+            .map(|(rule, client_name, message)| {
+                (
+                    (rule.to_string(), client_name.to_string()),
+                    message.to_string(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn window_within_day() {
+        let (from, to) = (time(8, 0), time(18, 0));
+        assert!(!is_allowed(from, to, time(7, 59)));
+        assert!(is_allowed(from, to, time(8, 0)));
+        assert!(is_allowed(from, to, time(12, 0)));
+        assert!(!is_allowed(from, to, time(18, 0)));
+        assert!(!is_allowed(from, to, time(23, 0)));
+    }
+
+    #[test]
+    fn window_spanning_midnight() {
+        let (from, to) = (time(22, 0), time(6, 0));
+        assert!(is_allowed(from, to, time(22, 0)));
+        assert!(is_allowed(from, to, time(23, 59)));
+        assert!(is_allowed(from, to, time(0, 0)));
+        assert!(is_allowed(from, to, time(5, 59)));
+        assert!(!is_allowed(from, to, time(6, 0)));
+        assert!(!is_allowed(from, to, time(12, 0)));
+        assert!(!is_allowed(from, to, time(21, 59)));
+    }
+
+    #[test]
+    fn empty_window() {
+        assert!(!is_allowed(time(8, 0), time(8, 0), time(8, 0)));
+        assert!(!is_allowed(time(8, 0), time(8, 0), time(12, 0)));
+    }
+
+    #[test]
+    fn update_raises_and_resolves() {
+        let alerts = Alerts::default();
+
+        let raised = alerts.update(active(&[("low", "a", "Battery at 15%")]));
+        assert_eq!(raised.len(), 1);
+        assert_eq!(raised[0].rule, "low");
+        assert_eq!(raised[0].client_name, "a");
+        assert_eq!(alerts.get_alerts().len(), 1);
+
+        // still active, only the message changes
+        let raised = alerts.update(active(&[
+            ("low", "a", "Battery at 12%"),
+            ("low", "b", "Battery at 18%"),
+        ]));
+        assert_eq!(raised.len(), 1);
+        assert_eq!(raised[0].client_name, "b");
+        let current = alerts.get_alerts();
+        assert_eq!(current.len(), 2);
+        assert_eq!(current[0].client_name, "a");
+        assert_eq!(current[0].message, "Battery at 12%");
+
+        let raised = alerts.update(active(&[("low", "b", "Battery at 18%")]));
+        assert!(raised.is_empty());
+        let current = alerts.get_alerts();
+        assert_eq!(current.len(), 1);
+        assert_eq!(current[0].client_name, "b");
+    }
+
+    #[test]
+    fn update_keeps_acknowledged_until_resolved() {
+        let alerts = Alerts::default();
+        alerts.update(active(&[("low", "a", "Battery at 15%")]));
+        alerts.acknowledge("low", "a");
+
+        let raised = alerts.update(active(&[("low", "a", "Battery at 14%")]));
+        assert!(raised.is_empty());
+        assert!(alerts.get_alerts()[0].acknowledged);
+
+        // raised again once it was resolved in between
+        alerts.update(HashMap::new());
+        let raised = alerts.update(active(&[("low", "a", "Battery at 13%")]));
+        assert_eq!(raised.len(), 1);
+        assert!(!alerts.get_alerts()[0].acknowledged);
+    }
+}
@@ -1,25 +1,239 @@
 extern crate gecko_audio_ctrl;
+use gecko_audio_ctrl::alerts::{self, Alerts};
 use gecko_audio_ctrl::client_state::ClientManager;
-use gecko_audio_ctrl::conf_store;
+use gecko_audio_ctrl::conf_store::{self, ConfStore};
+use gecko_audio_ctrl::connection::ConnectionRegistry;
+use gecko_audio_ctrl::daemon;
+use gecko_audio_ctrl::device_log::DeviceLogs;
+use gecko_audio_ctrl::logging;
+use gecko_audio_ctrl::registry::{self, DeviceRegistry};
+use gecko_audio_ctrl::settings::{self, Settings};
 use gecko_audio_ctrl::tcp_json;
-use gecko_audio_ctrl::ui;
+use gecko_audio_ctrl::tls;
+use gecko_audio_ctrl::ui::{self, ServerHandles};
+use gecko_audio_ctrl::ui_socket;
+use gecko_audio_ctrl::webhooks::{self, Webhooks};
 use gecko_audio_ctrl::zeroconf;
-use std::thread;
+use std::{env, fs, process, thread};
+use tracing::{info, warn};
 
 fn main() {
-    let client_manager = ClientManager::new();
-    zeroconf::start(9000);
+    let args: Vec<String> = env::args().collect();
+    let usage = format!("Usage: {} [options]\n\n{}", args[0], settings::OPTIONS);
+    let settings = match Settings::from_args(&args[1..]) {
+        Ok(Some(settings)) => settings,
+        Ok(None) => {
+            println!("{}", usage);
+            return;
+        }
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, usage);
+            process::exit(2);
+        }
+    };
+
+    // the server runs elsewhere, only its terminal ui is shown here
+    if settings.attach {
+        let path = match &settings.ui_socket {
+            Some(path) => path,
+            None => {
+                eprintln!("No ui socket to attach to");
+                process::exit(2);
+            }
+        };
+        if let Err(e) = ui_socket::attach(path) {
+            eprintln!("Could not attach to the server {}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    let device_logs = match &settings.device_log_dir {
+        Some(dir) => match DeviceLogs::new(
+            dir.clone(),
+            settings.device_log_max_size(),
+            settings.device_log_max_age(),
+        ) {
+            Ok(device_logs) => Some(device_logs),
+            Err(e) => {
+                eprintln!("Could not create the device log directory {}", e);
+                process::exit(2);
+            }
+        },
+        None => None,
+    };
+
+    // config loading runs in the background, fail before it starts
+    if let Err(e) = conf_store::create_dir(&settings.config_dir) {
+        eprintln!(
+            "Could not create the config directory {}: {}",
+            settings.config_dir.display(),
+            e
+        );
+        process::exit(2);
+    }
+
+    // the terminal ui shows the log itself, stdout belongs to it
+    let log_buffer = if settings.ui {
+        logging::init_buffer(&settings.log_filter, device_logs)
+    } else {
+        logging::init_writer(
+            &settings.log_filter,
+            settings.log_file.as_deref(),
+            device_logs,
+        )
+    };
+    let log_buffer = match log_buffer {
+        Ok(log_buffer) => log_buffer,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(2);
+        }
+    };
+
+    let rules = match alerts::load_rules(&settings.alert_rules) {
+        Ok(rules) => rules,
+        Err(e) => {
+            eprintln!("Could not load the alert rules {}", e);
+            process::exit(2);
+        }
+    };
+
+    let webhooks = match Webhooks::new(&settings.webhooks) {
+        Ok(webhooks) => webhooks,
+        Err(e) => {
+            eprintln!("Invalid webhook {}", e);
+            process::exit(2);
+        }
+    };
+
+    let client_manager = ClientManager::with_duplicate_policy(settings.duplicate_policy);
+    let connections = ConnectionRegistry::new();
+    let conf_store = ConfStore::new(settings.config_dir.clone());
+    let registry = DeviceRegistry::new(conf_store.clone());
+
+    let mut txt = Vec::new();
+    let tls = match settings.tls_port {
+        Some(tls_port) => {
+            let tls_identity = match tls::load_or_generate(&settings.tls_cert, &settings.tls_key) {
+                Ok(tls_identity) => tls_identity,
+                Err(e) => {
+                    eprintln!(
+                        "Could not load or generate the tls certificate {}: {}",
+                        settings.tls_cert.display(),
+                        e
+                    );
+                    process::exit(2);
+                }
+            };
+            txt.push(("tls_port".to_string(), tls_port.to_string()));
+            txt.push(("tls_sha256".to_string(), tls_identity.fingerprint));
+            Some((tls_port, tls_identity.config))
+        }
+        None => None,
+    };
+    zeroconf::start(settings.announce_name.clone(), settings.port, txt);
+
+    let listeners = tcp_json::listen(&settings.bind, settings.port, tls);
+    if listeners.report.bound.is_empty() {
+        for (addr, e) in listeners.report.failed.iter() {
+            eprintln!("Could not listen on {}: {}", addr, e);
+        }
+        eprintln!("Could not listen on any address");
+        process::exit(1);
+    }
+    let listen_report = listeners.report.clone();
+    for addr in listen_report.bound.iter() {
+        info!(addr = %addr, "Listening");
+    }
+    for (addr, e) in listen_report.failed.iter() {
+        warn!(addr = %addr, error = %e, "Could not listen");
+    }
+
+    if !webhooks.is_empty() {
+        let webhooks_client_manager = client_manager.clone();
+        let webhooks_webhooks = webhooks.clone();
+        thread::spawn(move || {
+            webhooks::run(webhooks_webhooks, webhooks_client_manager);
+        });
+    }
+
+    let registry_registry = registry.clone();
+    let registry_client_manager = client_manager.clone();
+    let registry_connections = connections.clone();
+    thread::spawn(move || {
+        registry::run(
+            registry_registry,
+            registry_client_manager,
+            registry_connections,
+        );
+    });
 
     let conf_client_manager = client_manager.clone();
+    let conf_conf_store = conf_store.clone();
+    let conf_webhooks = webhooks.clone();
     thread::spawn(move || {
-        conf_store::run(conf_client_manager);
+        conf_store::run(conf_conf_store, conf_client_manager, conf_webhooks);
     });
 
+    let alerts = Alerts::default();
+    info!(rules = rules.len(), "Alert rules loaded");
+    if !rules.is_empty() {
+        let alerts_client_manager = client_manager.clone();
+        let alerts_alerts = alerts.clone();
+        thread::spawn(move || {
+            alerts::run(rules, alerts_alerts, alerts_client_manager, webhooks);
+        });
+    }
+
     let tcp_client_manager = client_manager.clone();
+    let tcp_connections = connections.clone();
+    let tcp_conf_store = conf_store.clone();
+    let heartbeat_timeout = settings.heartbeat_timeout();
     thread::spawn(move || {
-        tcp_json::run(9000, tcp_client_manager);
+        tcp_json::run(
+            listeners,
+            heartbeat_timeout,
+            tcp_client_manager,
+            tcp_connections,
+            tcp_conf_store,
+        );
     });
 
-    let ui_client_manager = client_manager.clone();
-    ui::run(ui_client_manager);
+    let server = ServerHandles {
+        client_manager: client_manager.clone(),
+        connections: connections.clone(),
+        listen_report,
+        conf_store,
+        log_buffer,
+        alerts,
+        registry,
+    };
+    if settings.ui {
+        ui::run(server);
+        return;
+    }
+
+    let ui_socket = match &settings.ui_socket {
+        Some(path) => match ui_socket::listen(path) {
+            Ok(listener) => {
+                info!(path = %path.display(), "Terminals can attach");
+                thread::spawn(move || ui_socket::serve(listener, server));
+                Some(path)
+            }
+            Err(e) => {
+                eprintln!("Could not listen for terminals to attach {}", e);
+                process::exit(2);
+            }
+        },
+        None => None,
+    };
+    let res = daemon::run(client_manager, connections);
+    if let Some(path) = ui_socket {
+        let _ = fs::remove_file(path);
+    }
+    if let Err(e) = res {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
 }
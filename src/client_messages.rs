@@ -1,10 +1,29 @@
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+// version 1 is the protocol spoken by clients that do not send a protocol_version in Hello
+pub const PROTOCOL_VERSION: u32 = 2;
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+// optional features, the server only uses those the client announced as well
+pub const CAP_PING: &str = "ping";
+pub const CAP_ACK: &str = "ack";
+// the server sets the log level and asks for the log buffer of the client
+pub const CAP_LOG: &str = "log";
+pub const SERVER_CAPABILITIES: &[&str] = &[CAP_PING, CAP_ACK, CAP_LOG];
 
 // Messages by client
 
 #[derive(Deserialize, Clone, Debug)]
 pub struct Hello {
     pub client_name: String,
+    #[serde(default)]
+    pub protocol_version: Option<u32>,
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+    // secret of the device, checked against the token stored next to its config
+    #[serde(default)]
+    pub token: Option<String>,
 }
 
 #[derive(Deserialize, Clone, Debug)]
@@ -13,9 +32,50 @@ pub struct BatteryLevel {
     pub is_charging: bool,
 }
 
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    #[serde(alias = "warning")]
+    Warn,
+    Info,
+    Debug,
+    #[serde(alias = "verbose")]
+    Trace,
+}
+
+// only the message is required, clients that send nothing else log at info
 #[derive(Deserialize, Clone, Debug)]
 pub struct LogMsg {
     pub message: String,
+    #[serde(default)]
+    pub level: Option<LogLevel>,
+    // e.g. the android log tag
+    #[serde(default)]
+    pub tag: Option<String>,
+    // milliseconds since the unix epoch on the clock of the client
+    #[serde(default)]
+    pub timestamp: Option<i64>,
+    #[serde(default)]
+    pub fields: BTreeMap<String, serde_json::Value>,
+}
+
+// recent log lines of the client, sent in reply to RequestLogDump, possibly in several parts
+#[derive(Deserialize, Clone, Debug)]
+pub struct LogDump {
+    pub lines: Vec<LogMsg>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct Ack {
+    pub id: u64,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct Nack {
+    pub id: u64,
+    #[serde(default)]
+    pub reason: Option<String>,
 }
 
 // Messages by both
@@ -51,3 +111,71 @@ pub struct TransmitAudio {
 pub struct BatLogInterval {
     pub battery_log_interval_secs: Option<u32>,
 }
+
+// none restores the default of the client
+#[derive(Serialize, Clone, Debug)]
+pub struct ClientLogLevel {
+    pub log_level: Option<LogLevel>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Welcome {
+    pub protocol_version: u32,
+    pub capabilities: Vec<String>,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+pub enum ErrorCode {
+    InvalidJson,
+    InvalidMessage,
+    UnknownMessageType,
+    NotIdentified,
+    IncompatibleVersion,
+    Unauthorized,
+    PendingApproval,
+    AlreadyConnected,
+    Kicked,
+    Internal,
+}
+
+impl ErrorCode {
+    // the connection is closed after replying with a fatal error
+    pub fn is_fatal(&self) -> bool {
+        match self {
+            ErrorCode::InvalidJson
+            | ErrorCode::IncompatibleVersion
+            | ErrorCode::Unauthorized
+            | ErrorCode::AlreadyConnected
+            | ErrorCode::Kicked
+            | ErrorCode::Internal => true,
+            ErrorCode::InvalidMessage
+            | ErrorCode::UnknownMessageType
+            | ErrorCode::NotIdentified
+            | ErrorCode::PendingApproval => false,
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ErrorMsg {
+    pub code: ErrorCode,
+    pub message: String,
+    // type of the message that caused the error, if known
+    pub message_type: Option<String>,
+}
+
+impl ErrorMsg {
+    pub fn new(code: ErrorCode, message: &str) -> ErrorMsg {
+        ErrorMsg {
+            code,
+            message: message.to_string(),
+            message_type: None,
+        }
+    }
+}
+
+impl From<&str> for ErrorMsg {
+    fn from(message: &str) -> Self {
+        ErrorMsg::new(ErrorCode::Internal, message)
+    }
+}
@@ -1,9 +1,55 @@
+use crate::client_messages::LogLevel;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::net::SocketAddr;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::mpsc::{channel, Receiver, Sender};
 use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+// number of state changes kept per device
+const HISTORY_LEN: usize = 100;
+// number of battery reports kept per device
+const BATTERY_HISTORY_LEN: usize = 720;
+// the remaining runtime is estimated from the drain within this time
+const BATTERY_ESTIMATE_WINDOW: Duration = Duration::from_secs(30 * 60);
+// reports have to span at least this long for an estimate
+const BATTERY_ESTIMATE_MIN_SPAN: Duration = Duration::from_secs(2 * 60);
+
+// identifies a single connection, a device gets a new one whenever it reconnects
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SessionId(pub u64);
+
+impl fmt::Display for SessionId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+// what happens when a device logs in while it still has a session
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicatePolicy {
+    KickOld,
+    RejectNew,
+    AllowBoth,
+}
+
+// same names as in the settings file
+impl FromStr for DuplicatePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "kick_old" => Ok(DuplicatePolicy::KickOld),
+            "reject_new" => Ok(DuplicatePolicy::RejectNew),
+            "allow_both" => Ok(DuplicatePolicy::AllowBoth),
+            _ => Err(format!("Unknown duplicate policy {}", s)),
+        }
+    }
+}
 
 #[derive(Default, Clone, Deserialize, Serialize, Debug)]
 pub struct ClientState {
@@ -20,12 +66,17 @@ pub struct ClientState {
     pub send_audio: Option<bool>,
     pub recv_audio: Option<bool>,
     pub battery_log_interval_secs: Option<u32>,
+    pub log_level: Option<LogLevel>,
 }
 
 #[derive(Clone, Debug)]
 pub enum ClientStateChange {
     Add,
     Remove(ClientState),
+    // request to close the connection of a session, with a reason for the client
+    Kick(String),
+    // request for the recent log lines of the client
+    RequestLogDump,
 
     ClientName(String),
     BatteryLevel(f64),
@@ -40,41 +91,178 @@ pub enum ClientStateChange {
     SendAudio(bool),
     RecvAudio(bool),
     BatteryLogIntervalSecs(Option<u32>),
+    LogLevel(Option<LogLevel>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum CommandState {
+    Pending,
+    Acked,
+    Nacked(Option<String>),
+    TimedOut,
+}
+
+// a message sent to the client that the client has to acknowledge
+#[derive(Clone, Debug)]
+pub struct Command {
+    pub id: u64,
+    pub sent: Instant,
+    pub state: CommandState,
+}
+
+// an unknown device waiting for the operator to approve or reject it, or a known device whose
+// token is not stored yet
+#[derive(Clone, Debug)]
+pub struct PendingApproval {
+    pub client_name: String,
+    pub token: String,
+    pub since: Instant,
+    // the device has a config already, approving it only stores its token
+    pub pairing: bool,
+}
+
+// connection related state of a session that is not persisted in the config file
+#[derive(Clone, Debug)]
+pub struct SessionInfo {
+    // client name the session logged in as
+    pub device: Option<String>,
+    pub last_seen: Instant,
+    pub protocol_version: Option<u32>,
+    pub capabilities: Vec<String>,
+    // latest command per message type, a newer command supersedes the older one
+    pub commands: HashMap<&'static str, Command>,
+    pub pending_approval: Option<PendingApproval>,
+}
+
+impl SessionInfo {
+    fn new() -> Self {
+        SessionInfo {
+            device: None,
+            last_seen: Instant::now(),
+            protocol_version: None,
+            capabilities: Vec::new(),
+            commands: HashMap::new(),
+            pending_approval: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct BatterySample {
+    pub time: SystemTime,
+    pub level: f64,
+    pub is_charging: bool,
+}
+
+// a device is identified by its client name and outlives its sessions
+#[derive(Clone, Debug, Default)]
+pub struct Device {
+    pub sessions: Vec<SessionId>,
+    pub history: VecDeque<(SystemTime, ClientStateChange)>,
+    // every battery report, also those that did not change the level
+    pub battery: VecDeque<BatterySample>,
+}
+
+impl Device {
+    // linear fit of the level over the reports since the device was last charging. none while
+    // charging or if there are not enough reports yet.
+    pub fn battery_remaining(&self) -> Option<Duration> {
+        let last = self.battery.back()?;
+        if last.is_charging {
+            return None;
+        }
+        let samples: Vec<(f64, f64)> = self
+            .battery
+            .iter()
+            .rev()
+            .map_while(|sample| {
+                let age = last.time.duration_since(sample.time).ok()?;
+                if sample.is_charging || age > BATTERY_ESTIMATE_WINDOW {
+                    return None;
+                }
+                Some((-age.as_secs_f64(), sample.level))
+            })
+            .collect();
+        let span = -samples.last()?.0;
+        if span < BATTERY_ESTIMATE_MIN_SPAN.as_secs_f64() {
+            return None;
+        }
+
+        let n = samples.len() as f64;
+        let mean_time = samples.iter().map(|(time, _)| time).sum::<f64>() / n;
+        let mean_level = samples.iter().map(|(_, level)| level).sum::<f64>() / n;
+        let covariance: f64 = samples
+            .iter()
+            .map(|(time, level)| (time - mean_time) * (level - mean_level))
+            .sum();
+        let variance: f64 = samples
+            .iter()
+            .map(|(time, _)| (time - mean_time).powi(2))
+            .sum();
+        let drain = -covariance / variance;
+        if drain <= 0.0 {
+            return None;
+        }
+        Some(Duration::from_secs_f64(last.level / drain))
+    }
 }
 
+type ChangeSubscribers = Arc<Mutex<Vec<Sender<(SessionId, ClientStateChange)>>>>;
+
 #[derive(Clone)]
 pub struct ClientManager {
     // state of currently connected clients
-    connected_clients: Arc<RwLock<HashMap<SocketAddr, ClientState>>>,
+    connected_clients: Arc<RwLock<HashMap<SessionId, ClientState>>>,
+    // connection info of currently connected clients
+    sessions: Arc<RwLock<HashMap<SessionId, SessionInfo>>>,
+    // devices that logged in since the server started, by client name
+    devices: Arc<RwLock<HashMap<String, Device>>>,
+    duplicate_policy: DuplicatePolicy,
+    next_session_id: Arc<AtomicU64>,
+    next_command_id: Arc<AtomicU64>,
     // send session_id that was created, modified, deleted
-    change_sender: Sender<(SocketAddr, ClientStateChange)>,
-    subscribers: Arc<Mutex<Vec<Sender<(SocketAddr, ClientStateChange)>>>>,
+    change_sender: Sender<(SessionId, ClientStateChange)>,
+    subscribers: ChangeSubscribers,
+}
+
+impl Default for ClientManager {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ClientManager {
     pub fn new() -> ClientManager {
+        ClientManager::with_duplicate_policy(DuplicatePolicy::KickOld)
+    }
+
+    pub fn with_duplicate_policy(duplicate_policy: DuplicatePolicy) -> ClientManager {
         let connected_clients = Arc::new(Default::default());
-        let (change_sender, change_receiver) = channel::<(SocketAddr, ClientStateChange)>();
-        let subscribers: Arc<Mutex<Vec<Sender<(SocketAddr, ClientStateChange)>>>> =
-            Default::default();
+        let sessions = Arc::new(Default::default());
+        let (change_sender, change_receiver) = channel::<(SessionId, ClientStateChange)>();
+        let subscribers: ChangeSubscribers = Default::default();
 
         let subscribers_inner = subscribers.clone();
         thread::spawn(move || loop {
             let msg = change_receiver.recv().unwrap();
-            let subscribers_inner = subscribers_inner.lock().unwrap();
-            for sub in subscribers_inner.iter() {
-                sub.send(msg.clone()).unwrap();
-            }
+            // e.g. the ui of a terminal that was detached
+            let mut subscribers_inner = subscribers_inner.lock().unwrap();
+            subscribers_inner.retain(|sub| sub.send(msg.clone()).is_ok());
         });
 
         ClientManager {
             connected_clients,
+            sessions,
+            devices: Default::default(),
+            duplicate_policy,
+            next_session_id: Arc::new(AtomicU64::new(1)),
+            next_command_id: Arc::new(AtomicU64::new(1)),
             change_sender,
             subscribers,
         }
     }
 
-    pub fn get_change_receiver(&self) -> Receiver<(SocketAddr, ClientStateChange)> {
+    pub fn get_change_receiver(&self) -> Receiver<(SessionId, ClientStateChange)> {
         let (sender, receiver) = channel();
 
         let mut subscribers = self.subscribers.lock().unwrap();
@@ -83,29 +271,107 @@ impl ClientManager {
         receiver
     }
 
-    pub fn new_client(&mut self, session_id: SocketAddr) {
+    pub fn new_client(&mut self) -> SessionId {
+        let session_id = SessionId(self.next_session_id.fetch_add(1, Ordering::Relaxed));
         let mut connected_clients = self.connected_clients.write().unwrap();
         connected_clients.insert(session_id, Default::default());
-        self.change_sender
-            .send((session_id, ClientStateChange::Add))
-            .unwrap();
+        self.sessions
+            .write()
+            .unwrap()
+            .insert(session_id, SessionInfo::new());
+        self.notify(session_id, ClientStateChange::Add);
+        session_id
     }
 
-    pub fn rm_client(&mut self, session_id: SocketAddr) {
+    pub fn rm_client(&mut self, session_id: SessionId) {
         let mut connected_clients = self.connected_clients.write().unwrap();
         let client_state = match connected_clients.get_mut(&session_id) {
             Some(client_state) => client_state.clone(),
             None => return,
         };
         connected_clients.remove(&session_id);
-        self.change_sender
-            .send((session_id, ClientStateChange::Remove(client_state.clone())))
-            .unwrap();
+        // notify before the session is gone, so that the device history gets the removal
+        self.notify(session_id, ClientStateChange::Remove(client_state));
+        if let Some(session) = self.sessions.write().unwrap().remove(&session_id) {
+            if let Some(device) = session.device {
+                if let Some(device) = self.devices.write().unwrap().get_mut(&device) {
+                    device.sessions.retain(|id| *id != session_id);
+                }
+            }
+        }
+    }
+
+    pub fn kick_client(&self, session_id: SessionId, reason: &str) {
+        self.notify(session_id, ClientStateChange::Kick(reason.to_string()));
+    }
+
+    pub fn request_log_dump(&self, session_id: SessionId) {
+        self.notify(session_id, ClientStateChange::RequestLogDump);
+    }
+
+    // links the session to its device, applying the duplicate policy if the device already has a
+    // session. has to be called before the client name is set.
+    pub fn claim_device(
+        &self,
+        session_id: SessionId,
+        client_name: &str,
+    ) -> Result<(), &'static str> {
+        let mut old_session_ids = self.get_session_ids(client_name);
+        old_session_ids.retain(|id| *id != session_id);
+        if !old_session_ids.is_empty() && self.duplicate_policy == DuplicatePolicy::RejectNew {
+            Err("Device is already connected")?
+        }
+
+        let previous_device = {
+            let mut sessions = self.sessions.write().unwrap();
+            let session = match sessions.get_mut(&session_id) {
+                Some(session) => session,
+                None => Err("No session found")?,
+            };
+            session.device.replace(client_name.to_string())
+        };
+
+        {
+            let mut devices = self.devices.write().unwrap();
+            if let Some(previous_device) = previous_device {
+                if let Some(device) = devices.get_mut(&previous_device) {
+                    device.sessions.retain(|id| *id != session_id);
+                }
+            }
+            let device = devices.entry(client_name.to_string()).or_default();
+            device.sessions.push(session_id);
+        }
+
+        if self.duplicate_policy == DuplicatePolicy::KickOld {
+            for old_session_id in old_session_ids {
+                self.kick_client(old_session_id, "Device logged in again");
+            }
+        }
+        Ok(())
+    }
+
+    // sends the change to all subscribers and records it in the history of the device
+    fn notify(&self, session_id: SessionId, state_change: ClientStateChange) {
+        let device = match self.sessions.read().unwrap().get(&session_id) {
+            Some(session) => session.device.clone(),
+            None => None,
+        };
+        if let Some(device) = device {
+            if let Some(device) = self.devices.write().unwrap().get_mut(&device) {
+                device
+                    .history
+                    .push_back((SystemTime::now(), state_change.clone()));
+                if device.history.len() > HISTORY_LEN {
+                    device.history.pop_front();
+                }
+            }
+        }
+        self.change_sender.send((session_id, state_change)).unwrap();
     }
 
     pub fn set_client_property(
         &mut self,
-        session_id: SocketAddr,
+        session_id: SessionId,
         state_change: ClientStateChange,
     ) -> Result<(), &'static str> {
         let mut connected_clients = self.connected_clients.write().unwrap();
@@ -117,8 +383,11 @@ impl ClientManager {
         let has_changed;
 
         match state_change.clone() {
-            ClientStateChange::Add | ClientStateChange::Remove(_) => {
-                return Err("Remove and Add not supported");
+            ClientStateChange::Add
+            | ClientStateChange::Remove(_)
+            | ClientStateChange::Kick(_)
+            | ClientStateChange::RequestLogDump => {
+                return Err("Remove, Add, Kick and RequestLogDump not supported");
             }
             ClientStateChange::ClientName(client_name) => {
                 has_changed = client_state.client_name.as_ref() != Some(&client_name);
@@ -172,16 +441,20 @@ impl ClientManager {
                 has_changed = client_state.battery_log_interval_secs != battery_log_interval_secs;
                 client_state.battery_log_interval_secs = battery_log_interval_secs
             }
+            ClientStateChange::LogLevel(log_level) => {
+                has_changed = client_state.log_level != log_level;
+                client_state.log_level = log_level
+            }
         }
         if has_changed {
-            self.change_sender.send((session_id, state_change)).unwrap();
+            self.notify(session_id, state_change);
         }
         Ok(())
     }
 
     pub fn update_client(
         &mut self,
-        session_id: SocketAddr,
+        session_id: SessionId,
         changed: ClientState,
     ) -> Result<(), String> {
         let mut connected_clients = self.connected_clients.write().unwrap();
@@ -194,114 +467,91 @@ impl ClientManager {
 
         if let Some(client_name) = changed.client_name {
             if client_state.client_name.as_deref() != Some(&client_name) {
-                self.change_sender
-                    .send((session_id, ClientStateChange::ClientName(client_name)))
-                    .unwrap();
+                self.notify(session_id, ClientStateChange::ClientName(client_name));
             }
         }
         if let Some(battery_level) = changed.battery_level {
             if client_state.battery_level != changed.battery_level {
-                self.change_sender
-                    .send((session_id, ClientStateChange::BatteryLevel(battery_level)))
-                    .unwrap();
+                self.notify(session_id, ClientStateChange::BatteryLevel(battery_level));
             }
         }
         if let Some(is_charging) = changed.is_charging {
             if client_state.is_charging != changed.is_charging {
-                self.change_sender
-                    .send((session_id, ClientStateChange::IsCharging(is_charging)))
-                    .unwrap();
+                self.notify(session_id, ClientStateChange::IsCharging(is_charging));
             }
         }
         if let Some(display_name) = changed.display_name {
             if client_state.display_name.as_deref() != Some(&display_name) {
-                self.change_sender
-                    .send((session_id, ClientStateChange::DisplayName(display_name)))
-                    .unwrap();
+                self.notify(session_id, ClientStateChange::DisplayName(display_name));
             }
         }
         if let Some(recv_audio_port) = changed.recv_audio_port {
             if client_state.recv_audio_port != changed.recv_audio_port {
-                self.change_sender
-                    .send((
-                        session_id,
-                        ClientStateChange::RecvAudioPort(recv_audio_port),
-                    ))
-                    .unwrap();
+                self.notify(
+                    session_id,
+                    ClientStateChange::RecvAudioPort(recv_audio_port),
+                );
             }
         }
         if let Some(recv_repair_port) = changed.recv_repair_port {
             if client_state.recv_repair_port != changed.recv_repair_port {
-                self.change_sender
-                    .send((
-                        session_id,
-                        ClientStateChange::RecvRepairPort(recv_repair_port),
-                    ))
-                    .unwrap();
+                self.notify(
+                    session_id,
+                    ClientStateChange::RecvRepairPort(recv_repair_port),
+                );
             }
         }
         if let Some(send_audio_port) = changed.send_audio_port {
             if client_state.send_audio_port != changed.send_audio_port {
-                self.change_sender
-                    .send((
-                        session_id,
-                        ClientStateChange::SendAudioPort(send_audio_port),
-                    ))
-                    .unwrap();
+                self.notify(
+                    session_id,
+                    ClientStateChange::SendAudioPort(send_audio_port),
+                );
             }
         }
         if let Some(send_repair_port) = changed.send_repair_port {
             if client_state.send_repair_port != changed.send_repair_port {
-                self.change_sender
-                    .send((
-                        session_id,
-                        ClientStateChange::SendRepairPort(send_repair_port),
-                    ))
-                    .unwrap();
+                self.notify(
+                    session_id,
+                    ClientStateChange::SendRepairPort(send_repair_port),
+                );
             }
         }
         if let Some(send_mute) = changed.send_mute {
             if client_state.send_mute != changed.send_mute {
-                self.change_sender
-                    .send((session_id, ClientStateChange::SendMute(send_mute)))
-                    .unwrap();
+                self.notify(session_id, ClientStateChange::SendMute(send_mute));
             }
         }
         if let Some(recv_mute) = changed.recv_mute {
             if client_state.recv_mute != changed.recv_mute {
-                self.change_sender
-                    .send((session_id, ClientStateChange::RecvMute(recv_mute)))
-                    .unwrap();
+                self.notify(session_id, ClientStateChange::RecvMute(recv_mute));
             }
         }
         if let Some(send_audio) = changed.send_audio {
             if client_state.send_audio != changed.send_audio {
-                self.change_sender
-                    .send((session_id, ClientStateChange::SendAudio(send_audio)))
-                    .unwrap();
+                self.notify(session_id, ClientStateChange::SendAudio(send_audio));
             }
         }
         if let Some(recv_audio) = changed.recv_audio {
             if client_state.recv_audio != changed.recv_audio {
-                self.change_sender
-                    .send((session_id, ClientStateChange::RecvAudio(recv_audio)))
-                    .unwrap();
+                self.notify(session_id, ClientStateChange::RecvAudio(recv_audio));
             }
         }
 
         if client_state.battery_log_interval_secs != changed.battery_log_interval_secs {
-            self.change_sender
-                .send((
-                    session_id,
-                    ClientStateChange::BatteryLogIntervalSecs(changed.battery_log_interval_secs),
-                ))
-                .unwrap();
+            self.notify(
+                session_id,
+                ClientStateChange::BatteryLogIntervalSecs(changed.battery_log_interval_secs),
+            );
+        }
+        if client_state.log_level != changed.log_level {
+            self.notify(session_id, ClientStateChange::LogLevel(changed.log_level));
         }
 
         Ok(())
     }
 
-    pub fn get_client(&mut self, session_id: SocketAddr) -> Result<ClientState, &'static str> {
+    pub fn get_client(&mut self, session_id: SessionId) -> Result<ClientState, &'static str> {
         let connected_clients = self.connected_clients.read().unwrap();
         match connected_clients.get(&session_id) {
             Some(client_state) => Ok((*client_state).clone()),
@@ -309,21 +559,287 @@ impl ClientManager {
         }
     }
 
-    pub fn get_session_id(&self, client_name: &str) -> Option<SocketAddr> {
-        let connected_clients = self.connected_clients.read().unwrap();
-        for (session_id, state) in connected_clients.iter() {
-            if let Some(connected_client_name) = state.client_name.clone() {
-                if connected_client_name == client_name {
-                    return Some(session_id.clone());
+    // latest session of the device
+    pub fn get_session_id(&self, client_name: &str) -> Option<SessionId> {
+        self.get_session_ids(client_name).last().cloned()
+    }
+
+    // more than one if the duplicate policy allows it
+    pub fn get_session_ids(&self, client_name: &str) -> Vec<SessionId> {
+        let devices = self.devices.read().unwrap();
+        match devices.get(client_name) {
+            Some(device) => device.sessions.clone(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn record_battery(&self, session_id: SessionId, level: f64, is_charging: bool) {
+        let device = match self.sessions.read().unwrap().get(&session_id) {
+            Some(session) => session.device.clone(),
+            None => None,
+        };
+        if let Some(device) = device {
+            if let Some(device) = self.devices.write().unwrap().get_mut(&device) {
+                device.battery.push_back(BatterySample {
+                    time: SystemTime::now(),
+                    level,
+                    is_charging,
+                });
+                if device.battery.len() > BATTERY_HISTORY_LEN {
+                    device.battery.pop_front();
                 }
             }
         }
+    }
 
-        None
+    pub fn get_device(&self, client_name: &str) -> Option<Device> {
+        self.devices.read().unwrap().get(client_name).cloned()
     }
 
     pub fn get_all_clients(&self) -> Vec<ClientState> {
         let connected_clients = self.connected_clients.read().unwrap();
         connected_clients.values().cloned().collect()
     }
+
+    pub fn get_all_sessions(&self) -> Vec<(SessionId, ClientState)> {
+        let connected_clients = self.connected_clients.read().unwrap();
+        connected_clients
+            .iter()
+            .map(|(session_id, state)| (*session_id, state.clone()))
+            .collect()
+    }
+
+    // record that the session sent something, used for dead client detection
+    pub fn touch_client(&self, session_id: SessionId) {
+        if let Some(session) = self.sessions.write().unwrap().get_mut(&session_id) {
+            session.last_seen = Instant::now();
+        }
+    }
+
+    pub fn get_last_seen(&self, session_id: SessionId) -> Option<Instant> {
+        let sessions = self.sessions.read().unwrap();
+        sessions.get(&session_id).map(|session| session.last_seen)
+    }
+
+    pub fn get_silent_clients(&self, timeout: Duration) -> Vec<SessionId> {
+        let sessions = self.sessions.read().unwrap();
+        sessions
+            .iter()
+            .filter(|(_, session)| session.last_seen.elapsed() > timeout)
+            .map(|(session_id, _)| *session_id)
+            .collect()
+    }
+
+    pub fn set_protocol(
+        &self,
+        session_id: SessionId,
+        protocol_version: u32,
+        capabilities: Vec<String>,
+    ) -> Result<(), &'static str> {
+        let mut sessions = self.sessions.write().unwrap();
+        let session = match sessions.get_mut(&session_id) {
+            Some(session) => session,
+            None => Err("No session found")?,
+        };
+        session.protocol_version = Some(protocol_version);
+        session.capabilities = capabilities;
+        Ok(())
+    }
+
+    pub fn has_capability(&self, session_id: SessionId, capability: &str) -> bool {
+        let sessions = self.sessions.read().unwrap();
+        match sessions.get(&session_id) {
+            Some(session) => session.capabilities.iter().any(|c| c == capability),
+            None => false,
+        }
+    }
+
+    pub fn get_session_info(&self, session_id: SessionId) -> Option<SessionInfo> {
+        self.sessions.read().unwrap().get(&session_id).cloned()
+    }
+
+    pub fn add_command(
+        &self,
+        session_id: SessionId,
+        message_type: &'static str,
+    ) -> Result<u64, &'static str> {
+        let mut sessions = self.sessions.write().unwrap();
+        let session = match sessions.get_mut(&session_id) {
+            Some(session) => session,
+            None => Err("No session found")?,
+        };
+        let id = self.next_command_id.fetch_add(1, Ordering::Relaxed);
+        session.commands.insert(
+            message_type,
+            Command {
+                id,
+                sent: Instant::now(),
+                state: CommandState::Pending,
+            },
+        );
+        Ok(id)
+    }
+
+    pub fn set_command_state(
+        &self,
+        session_id: SessionId,
+        id: u64,
+        state: CommandState,
+    ) -> Result<(), &'static str> {
+        let mut sessions = self.sessions.write().unwrap();
+        let session = match sessions.get_mut(&session_id) {
+            Some(session) => session,
+            None => Err("No session found")?,
+        };
+        // acks for superseded commands are ignored
+        if let Some(command) = session
+            .commands
+            .values_mut()
+            .find(|command| command.id == id)
+        {
+            command.state = state;
+        }
+        Ok(())
+    }
+
+    pub fn expire_commands(&self, timeout: Duration) {
+        let mut sessions = self.sessions.write().unwrap();
+        for session in sessions.values_mut() {
+            for command in session.commands.values_mut() {
+                if command.state == CommandState::Pending && command.sent.elapsed() > timeout {
+                    command.state = CommandState::TimedOut;
+                }
+            }
+        }
+    }
+
+    pub fn set_pending_approval(
+        &self,
+        session_id: SessionId,
+        pending_approval: Option<PendingApproval>,
+    ) -> Result<(), &'static str> {
+        let mut sessions = self.sessions.write().unwrap();
+        match sessions.get_mut(&session_id) {
+            Some(session) => {
+                session.pending_approval = pending_approval;
+                Ok(())
+            }
+            None => Err("No session found"),
+        }
+    }
+
+    // oldest first
+    pub fn get_pending_approvals(&self) -> Vec<(SessionId, PendingApproval)> {
+        let sessions = self.sessions.read().unwrap();
+        let mut pending_approvals: Vec<(SessionId, PendingApproval)> = sessions
+            .iter()
+            .filter_map(|(session_id, session)| {
+                session
+                    .pending_approval
+                    .clone()
+                    .map(|pending_approval| (*session_id, pending_approval))
+            })
+            .collect();
+        pending_approvals.sort_by_key(|(_, pending_approval)| pending_approval.since);
+        pending_approvals
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    // samples as (seconds, level, is_charging)
+    fn with_samples(samples: &[(u64, f64, bool)]) -> Device {
+        let start = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        Device {
+            battery: samples
+                .iter()
+                .map(|&(secs, level, is_charging)| BatterySample {
+                    time: start + Duration::from_secs(secs),
+                    level,
+                    is_charging,
+                })
+                .collect(),
+            ..Device::default()
+        }
+    }
+
+    fn minutes(remaining: Option<Duration>) -> f64 {
+        remaining.unwrap().as_secs_f64() / 60.0
+    }
+
+    #[test]
+    fn battery_remaining_steady_drain() {
+        // one percent a minute
+        let device = with_samples(&[
+            (0, 50.0, false),
+            (60, 49.0, false),
+            (120, 48.0, false),
+            (180, 47.0, false),
+        ]);
+        assert!((minutes(device.battery_remaining()) - 47.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn battery_remaining_starts_after_last_charging_sample() {
+        let device = with_samples(&[
+            (0, 20.0, false),
+            (60, 30.0, true),
+            (120, 40.0, true),
+            (180, 40.0, false),
+            (240, 39.0, false),
+            (300, 38.0, false),
+        ]);
+        assert!((minutes(device.battery_remaining()) - 38.0).abs() < 0.01);
+
+        // the charging sample itself is not used, leaving less than the minimum span
+        let device = with_samples(&[(0, 30.0, true), (60, 29.0, false), (120, 28.0, false)]);
+        assert_eq!(device.battery_remaining(), None);
+    }
+
+    #[test]
+    fn battery_remaining_none_while_charging() {
+        let device = with_samples(&[(0, 50.0, false), (300, 45.0, false), (360, 46.0, true)]);
+        assert_eq!(device.battery_remaining(), None);
+    }
+
+    #[test]
+    fn battery_remaining_ignores_samples_outside_window() {
+        // the first sample is 50 minutes old and would halve the drain
+        let device = with_samples(&[
+            (0, 60.0, false),
+            (40 * 60, 50.0, false),
+            (45 * 60, 45.0, false),
+            (50 * 60, 40.0, false),
+        ]);
+        assert!((minutes(device.battery_remaining()) - 40.0).abs() < 0.01);
+
+        // a sample exactly at the edge of the window is still used
+        let device = with_samples(&[(0, 60.0, false), (30 * 60, 30.0, false)]);
+        assert!((minutes(device.battery_remaining()) - 30.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn battery_remaining_needs_min_span() {
+        let device = with_samples(&[(0, 50.0, false), (60, 49.0, false), (119, 48.0, false)]);
+        assert_eq!(device.battery_remaining(), None);
+
+        let device = with_samples(&[(0, 50.0, false), (60, 49.0, false), (120, 48.0, false)]);
+        assert!((minutes(device.battery_remaining()) - 48.0).abs() < 0.01);
+
+        assert_eq!(with_samples(&[]).battery_remaining(), None);
+        assert_eq!(with_samples(&[(0, 50.0, false)]).battery_remaining(), None);
+    }
+
+    #[test]
+    fn battery_remaining_none_without_drain() {
+        let device = with_samples(&[(0, 50.0, false), (300, 50.0, false), (600, 50.0, false)]);
+        assert_eq!(device.battery_remaining(), None);
+
+        // rising without charging, e.g. a recalibrating battery
+        let device = with_samples(&[(0, 50.0, false), (300, 51.0, false), (600, 52.0, false)]);
+        assert_eq!(device.battery_remaining(), None);
+    }
 }
@@ -1,31 +1,64 @@
-use crate::client_state::{ClientManager, ClientState, ClientStateChange};
+use crate::client_state::{
+    ClientManager, ClientState, ClientStateChange, PendingApproval, SessionId,
+};
+use crate::webhooks::{Event, Webhooks};
 use notify::DebouncedEvent;
 use notify::{watcher, RecursiveMode, Watcher};
 use std::fs::{File, OpenOptions};
-use std::io::BufReader;
-use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
 use std::sync::mpsc::{channel, Sender};
 use std::time::Duration;
 use std::{fs, thread};
+use tracing::{debug, error, info, warn};
 
-pub fn run(client_manager: ClientManager) {
+// names starting with a dot are no valid client names, so these never clash with a device
+const TEMPLATE_FILE: &str = ".template.json";
+const BLOCKLIST_FILE: &str = ".blocklist";
+const WRITE_TEST_FILE: &str = ".write_test";
+
+// the directory holding the client configs, tokens, template and blocklist
+#[derive(Clone, Debug)]
+pub struct ConfStore {
+    dir: PathBuf,
+}
+
+// the directory is created by main
+pub fn run(conf_store: ConfStore, client_manager: ClientManager, webhooks: Webhooks) {
     let (file_tx, file_rx) = channel();
-    let mut watcher = watcher(file_tx, Duration::from_secs(1)).unwrap();
-    watcher
-        .watch("./client_config/", RecursiveMode::NonRecursive)
-        .unwrap();
+    // watches until it is dropped, without it the configs are still loaded on login
+    let _watcher = match watcher(file_tx, Duration::from_secs(1)) {
+        Ok(mut watcher) => match watcher.watch(&conf_store.dir, RecursiveMode::NonRecursive) {
+            Ok(_) => Some(watcher),
+            Err(e) => {
+                error!(path = %conf_store.dir.display(), error = %e, "Could not watch the config directory, changed configs are not applied");
+                None
+            }
+        },
+        Err(e) => {
+            error!(error = %e, "Could not watch the config directory, changed configs are not applied");
+            None
+        }
+    };
+
+    warn_invalid_names(&conf_store.dir);
 
     let (conf_tx, conf_rx) = channel();
 
     let file_conf_tx = conf_tx.clone();
+    let file_conf_store = conf_store.clone();
     thread::spawn(move || loop {
-        match file_rx.recv().unwrap() {
-            DebouncedEvent::NoticeWrite(path)
-            | DebouncedEvent::Create(path)
+        let event = match file_rx.recv() {
+            Ok(event) => event,
+            Err(_) => return,
+        };
+        // not NoticeWrite, that comes while the file is still being written
+        match event {
+            DebouncedEvent::Create(path)
             | DebouncedEvent::Write(path)
             | DebouncedEvent::Rename(_, path) => file_conf_tx.send(path).unwrap(),
-            DebouncedEvent::Rescan => scan_dir(&file_conf_tx),
+            DebouncedEvent::Rescan => scan_dir(&file_conf_store.dir, &file_conf_tx),
             _ => {}
         }
     });
@@ -36,10 +69,29 @@ pub fn run(client_manager: ClientManager) {
         loop {
             let path = conf_rx.recv().unwrap();
 
-            let client_config = match read_config_file(path) {
+            if has_invalid_name(&path) {
+                warn!(path = %path.display(), "Invalid client name in config file name, ignoring");
+                continue;
+            }
+            // tokens, the template and the blocklist live in the same directory
+            let is_client_config = path.extension().is_some_and(|ext| ext == "json")
+                && path
+                    .file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .is_some_and(is_valid_client_name);
+            if !is_client_config {
+                debug!(path = %path.display(), "Not a client config, ignoring");
+                continue;
+            }
+
+            let client_config = match read_config_file(path.clone()) {
                 Ok(conf) => conf,
-                Err(_e) => {
-                    //log(e)
+                Err(e) => {
+                    warn!(path = %path.display(), error = %e, "Could not read config, ignoring");
+                    webhooks.notify(Event::ConfigRejected {
+                        path: path.display().to_string(),
+                        error: e,
+                    });
                     continue;
                 }
             };
@@ -47,18 +99,31 @@ pub fn run(client_manager: ClientManager) {
             let client_name = match client_config.client_name.clone() {
                 Some(client_name) => client_name,
                 None => {
-                    // log("no client name set in config, ignoring")
+                    warn!(path = %path.display(), "No client_name set in config, ignoring");
+                    webhooks.notify(Event::ConfigRejected {
+                        path: path.display().to_string(),
+                        error: "No client_name set".to_string(),
+                    });
                     continue;
                 }
             };
 
-            let session_id = match client_manager_f_change.get_session_id(&client_name) {
-                Some(session_id) => session_id,
-                None => continue, // client not connected maybe log as trace
-            };
-
-            if let Err(_e) = client_manager_f_change.update_client(session_id, client_config) {
-                //log(e)
+            let session_ids = client_manager_f_change.get_session_ids(&client_name);
+            if session_ids.is_empty() {
+                debug!(client_name = %client_name, "Config changed, client is not connected");
+            }
+            for session_id in session_ids {
+                info!(session = %session_id, client_name = %client_name, "Config changed");
+                if let Err(e) =
+                    client_manager_f_change.update_client(session_id, client_config.clone())
+                {
+                    warn!(
+                        session = %session_id,
+                        client_name = %client_name,
+                        error = %e,
+                        "Could not apply config"
+                    );
+                }
             }
         }
     });
@@ -68,16 +133,30 @@ pub fn run(client_manager: ClientManager) {
 
     loop {
         let (session_id, state_change) = client_state_change_receiver.recv().unwrap();
-        if let Err(_e) =
-            handle_client_state_change(session_id, state_change, &mut client_manager_state_change)
-        {
-
-            //log(e)
+        if let Err(e) = handle_client_state_change(
+            &conf_store,
+            session_id,
+            state_change,
+            &mut client_manager_state_change,
+        ) {
+            warn!(session = %session_id, error = %e, "Could not load or persist config");
         }
     }
 }
 
-fn read_config_file(file_path: PathBuf) -> Result<ClientState, String> {
+// creates the config directory if needed and checks that configs can be written to it
+pub fn create_dir(dir: &Path) -> Result<(), String> {
+    if let Err(e) = fs::create_dir_all(dir) {
+        Err(e.to_string())?
+    }
+    let path = dir.join(WRITE_TEST_FILE);
+    if let Err(e) = fs::write(&path, b"").and_then(|_| fs::remove_file(&path)) {
+        Err(e.to_string())?
+    }
+    Ok(())
+}
+
+pub fn read_config_file(file_path: PathBuf) -> Result<ClientState, String> {
     match file_path.extension() {
         None => Err("no file extension")?,
         Some(ext) => {
@@ -87,13 +166,7 @@ fn read_config_file(file_path: PathBuf) -> Result<ClientState, String> {
         }
     }
 
-    let file = OpenOptions::new()
-        .read(true)
-        .create(true)
-        .write(true)
-        .open(file_path);
-
-    let file = match file {
+    let file = match File::open(file_path) {
         Ok(res) => res,
         Err(e) => Err(e.to_string())?,
     };
@@ -106,18 +179,18 @@ fn read_config_file(file_path: PathBuf) -> Result<ClientState, String> {
 }
 
 fn handle_client_state_change(
-    session_id: SocketAddr,
+    conf_store: &ConfStore,
+    session_id: SessionId,
     state_change: ClientStateChange,
     client_manager: &mut ClientManager,
 ) -> Result<(), String> {
     match state_change {
-        ClientStateChange::Remove(_) | ClientStateChange::Add => Ok(()), // we do not hold any state regarding connected clients
+        ClientStateChange::Remove(_)
+        | ClientStateChange::Add
+        | ClientStateChange::Kick(_)
+        | ClientStateChange::RequestLogDump => Ok(()), // we do not hold any state regarding connected clients
         ClientStateChange::ClientName(client_name) => {
-            let mut conf_path = PathBuf::from("./client_config/");
-            let mut filename = client_name.clone();
-            filename.push_str(".json");
-            conf_path.push(filename);
-            let client_config = read_config_file(conf_path)?;
+            let client_config = read_config_file(conf_store.client_file(&client_name, "json"))?;
             client_manager.update_client(session_id, client_config)
         }
         _ => {
@@ -126,26 +199,235 @@ fn handle_client_state_change(
                 Some(client_name) => client_name,
                 None => Err("Client name not set")?,
             };
-            let mut conf_path = PathBuf::from("./client_config/");
-            let mut filename = client_name.clone();
-            filename.push_str(".json");
-            conf_path.push(filename);
-            let file = match File::create(conf_path) {
-                Ok(res) => res,
-                Err(e) => Err(e.to_string())?,
+            write_config_file(
+                &conf_store.client_file(&client_name, "json"),
+                &client_config,
+            )
+        }
+    }
+}
+
+// writes a temporary file next to the config and renames it, so that the watcher never reads a
+// half written config. the name of the temporary file is no client config.
+fn write_config_file(path: &Path, client_config: &ClientState) -> Result<(), String> {
+    let file_name = match path.file_name().and_then(|name| name.to_str()) {
+        Some(file_name) => file_name,
+        None => Err("Invalid config path")?,
+    };
+    let tmp_path = path.with_file_name(format!(".{}.tmp", file_name));
+    let json = match serde_json::to_vec_pretty(client_config) {
+        Ok(json) => json,
+        Err(e) => Err(e.to_string())?,
+    };
+    if let Err(e) = fs::write(&tmp_path, json).and_then(|_| fs::rename(&tmp_path, path)) {
+        let _ = fs::remove_file(&tmp_path);
+        Err(e.to_string())?
+    }
+    Ok(())
+}
+
+#[derive(Debug, PartialEq)]
+pub enum DeviceStatus {
+    Known,
+    // has a config but no token, e.g. configs from before tokens were introduced
+    Unpaired,
+    Unknown,
+    Blocked,
+}
+
+impl ConfStore {
+    pub fn new(dir: PathBuf) -> Self {
+        ConfStore { dir }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn device_status(&self, client_name: &str) -> Result<DeviceStatus, String> {
+        if !is_valid_client_name(client_name) {
+            Err("Invalid client name")?
+        }
+
+        Ok(
+            if self
+                .read_blocklist()
+                .iter()
+                .any(|blocked| blocked == client_name)
+            {
+                DeviceStatus::Blocked
+            } else if self.client_file(client_name, "json").exists() {
+                if self.client_file(client_name, "token").exists() {
+                    DeviceStatus::Known
+                } else {
+                    DeviceStatus::Unpaired
+                }
+            } else {
+                DeviceStatus::Unknown
+            },
+        )
+    }
+
+    // creates the config of a pending device from the template and announces its name, which
+    // loads the config like for any known device. a known device keeps its config.
+    pub fn approve_device(
+        &self,
+        client_manager: &mut ClientManager,
+        session_id: SessionId,
+        pending_approval: PendingApproval,
+    ) -> Result<(), String> {
+        let client_name = pending_approval.client_name;
+        let config_file = self.client_file(&client_name, "json");
+        if !config_file.exists() {
+            let template_file = self.dir.join(TEMPLATE_FILE);
+            let mut client_config = match fs::metadata(&template_file) {
+                Ok(_) => read_config_file(template_file)?,
+                Err(_) => ClientState::default(),
             };
+            client_config.client_name = Some(client_name.clone());
+            write_config_file(&config_file, &client_config)?;
+        }
+        // a token left over from a removed config belongs to whatever device used the name
+        // before, the operator approved the token of this one
+        let _ = fs::remove_file(self.client_file(&client_name, "token"));
+        self.store_token(&client_name, &pending_approval.token)?;
+
+        client_manager.set_pending_approval(session_id, None)?;
+        info!(session = %session_id, client_name = %client_name, "Device approved");
+        client_manager.claim_device(session_id, &client_name)?;
+        client_manager
+            .set_client_property(session_id, ClientStateChange::ClientName(client_name))?;
+        Ok(())
+    }
+
+    pub fn reject_device(
+        &self,
+        client_manager: &mut ClientManager,
+        session_id: SessionId,
+        pending_approval: PendingApproval,
+        block: bool,
+    ) -> Result<(), String> {
+        // the name belongs to a configured device, blocking it would lock that device out
+        if block && pending_approval.pairing {
+            Err("A known device can not be blocked, reject it with r")?
+        }
+        if block {
+            let file = OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(self.dir.join(BLOCKLIST_FILE));
+            let res = file.and_then(|mut file| writeln!(file, "{}", pending_approval.client_name));
+            if let Err(e) = res {
+                Err(e.to_string())?
+            }
+        }
+        client_manager.set_pending_approval(session_id, None)?;
+        info!(
+            session = %session_id,
+            client_name = %pending_approval.client_name,
+            blocked = block,
+            "Device rejected"
+        );
+        client_manager.kick_client(session_id, "Rejected by the operator");
+        Ok(())
+    }
+
+    fn read_blocklist(&self) -> Vec<String> {
+        match File::open(self.dir.join(BLOCKLIST_FILE)) {
+            Ok(file) => BufReader::new(file)
+                .lines()
+                .map_while(Result::ok)
+                .map(|line| line.trim().to_string())
+                .filter(|line| !line.is_empty())
+                .collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    // the token of a device is stored as <client_name>.token next to its config. a device without
+    // a token is paired by the operator approving it, see DeviceStatus::Unpaired.
+    pub fn authenticate(&self, client_name: &str, token: &str) -> Result<(), String> {
+        if !is_valid_client_name(client_name) {
+            Err("Invalid client name")?
+        }
+        if token.is_empty() {
+            Err("No token sent")?
+        }
+
+        let token_path = self.client_file(client_name, "token");
+        match fs::read_to_string(&token_path) {
+            Ok(stored_token) => {
+                if tokens_match(stored_token.trim().as_bytes(), token.as_bytes()) {
+                    Ok(())
+                } else {
+                    Err("Invalid token".to_string())
+                }
+            }
+            Err(_) => Err("Device is not paired".to_string()),
+        }
+    }
+
+    fn store_token(&self, client_name: &str, token: &str) -> Result<(), String> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(self.client_file(client_name, "token"));
+        match file.and_then(|mut file| file.write_all(token.as_bytes())) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    pub fn client_file(&self, client_name: &str, extension: &str) -> PathBuf {
+        self.dir.join(format!("{}.{}", client_name, extension))
+    }
+}
+
+// client names are used as file names
+pub fn is_valid_client_name(client_name: &str) -> bool {
+    !client_name.is_empty()
+        && !client_name.starts_with('.')
+        && client_name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+// compares in constant time to not leak how much of the token was correct
+fn tokens_match(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// a json file that is no file of the server (those start with a dot) but not named after a valid
+// client name, e.g. from before client names were restricted
+fn has_invalid_name(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "json")
+        && match path.file_stem().and_then(|stem| stem.to_str()) {
+            Some(stem) => !stem.starts_with('.') && !is_valid_client_name(stem),
+            None => true,
+        }
+}
 
-            match serde_json::to_writer_pretty(file, &client_config) {
-                Ok(_) => Ok(()),
-                Err(e) => Err(e.to_string()),
+// the watcher only reports changes, configs that were there before are found here
+fn warn_invalid_names(dir: &Path) {
+    if let Ok(entries) = fs::read_dir(dir) {
+        for path in entries.filter_map(Result::ok).map(|entry| entry.path()) {
+            if has_invalid_name(&path) {
+                warn!(path = %path.display(), "Invalid client name in config file name, ignoring");
             }
         }
     }
 }
 
-fn scan_dir(file_tx: &Sender<PathBuf>) {
-    for entry in fs::read_dir("./client_config/").unwrap() {
-        let entry = entry.unwrap();
+fn scan_dir(dir: &Path, file_tx: &Sender<PathBuf>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!(path = %dir.display(), error = %e, "Could not read the config directory");
+            return;
+        }
+    };
+    for entry in entries.filter_map(Result::ok) {
         let path = entry.path();
         if path.is_dir() {
             continue;
@@ -154,3 +436,218 @@ fn scan_dir(file_tx: &Sender<PathBuf>) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use std::process;
+    use std::time::Instant;
+
+    // a fresh directory per test, removed when the test ends
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("gecko_conf_store_{}_{}", process::id(), name));
+            let _ = fs::remove_dir_all(&dir);
+            create_dir(&dir).unwrap();
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn pending(client_name: &str, token: &str, pairing: bool) -> PendingApproval {
+        PendingApproval {
+            client_name: client_name.to_string(),
+            token: token.to_string(),
+            since: Instant::now(),
+            pairing,
+        }
+    }
+
+    // a session that sent hello and waits for the operator
+    fn pending_session(
+        client_manager: &mut ClientManager,
+        pending_approval: &PendingApproval,
+    ) -> SessionId {
+        let session_id = client_manager.new_client();
+        client_manager
+            .set_pending_approval(session_id, Some(pending_approval.clone()))
+            .unwrap();
+        session_id
+    }
+
+    #[test]
+    fn client_names() {
+        for client_name in ["phone", "Phone-2", "a_b.c", "x"] {
+            assert!(is_valid_client_name(client_name), "{}", client_name);
+        }
+        for client_name in ["", ".template", "..", "../phone", "a/b", "my phone", "ä"] {
+            assert!(!is_valid_client_name(client_name), "{}", client_name);
+        }
+    }
+
+    #[test]
+    fn device_status() {
+        let dir = TempDir::new("device_status");
+        let conf_store = ConfStore::new(dir.0.clone());
+        assert!(conf_store.device_status("../phone").is_err());
+        assert_eq!(conf_store.device_status("phone"), Ok(DeviceStatus::Unknown));
+
+        fs::write(conf_store.client_file("phone", "json"), "{}").unwrap();
+        assert_eq!(
+            conf_store.device_status("phone"),
+            Ok(DeviceStatus::Unpaired)
+        );
+
+        fs::write(conf_store.client_file("phone", "token"), "secret").unwrap();
+        assert_eq!(conf_store.device_status("phone"), Ok(DeviceStatus::Known));
+
+        fs::write(dir.0.join(BLOCKLIST_FILE), "other\n phone \n").unwrap();
+        assert_eq!(conf_store.device_status("phone"), Ok(DeviceStatus::Blocked));
+        assert_eq!(
+            conf_store.device_status("tablet"),
+            Ok(DeviceStatus::Unknown)
+        );
+    }
+
+    #[test]
+    fn authenticate() {
+        let dir = TempDir::new("authenticate");
+        let conf_store = ConfStore::new(dir.0.clone());
+        assert_eq!(
+            conf_store.authenticate("phone", "secret"),
+            Err("Device is not paired".to_string())
+        );
+
+        fs::write(conf_store.client_file("phone", "token"), "secret\n").unwrap();
+        assert_eq!(conf_store.authenticate("phone", "secret"), Ok(()));
+        assert_eq!(
+            conf_store.authenticate("phone", "secreT"),
+            Err("Invalid token".to_string())
+        );
+        assert_eq!(
+            conf_store.authenticate("phone", "secret2"),
+            Err("Invalid token".to_string())
+        );
+        assert!(conf_store.authenticate("phone", "").is_err());
+        assert!(conf_store.authenticate("../phone", "secret").is_err());
+    }
+
+    #[test]
+    fn approve_unknown_device() {
+        let dir = TempDir::new("approve_unknown");
+        let conf_store = ConfStore::new(dir.0.clone());
+        fs::write(dir.0.join(TEMPLATE_FILE), r#"{"display_name":"New"}"#).unwrap();
+        let mut client_manager = ClientManager::new();
+        let changes = client_manager.get_change_receiver();
+        let pending_approval = pending("phone", "secret", false);
+        let session_id = pending_session(&mut client_manager, &pending_approval);
+
+        conf_store
+            .approve_device(&mut client_manager, session_id, pending_approval)
+            .unwrap();
+
+        let config = read_config_file(conf_store.client_file("phone", "json")).unwrap();
+        assert_eq!(config.client_name.as_deref(), Some("phone"));
+        assert_eq!(config.display_name.as_deref(), Some("New"));
+        let token_file = conf_store.client_file("phone", "token");
+        assert_eq!(
+            fs::metadata(&token_file).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        assert_eq!(conf_store.authenticate("phone", "secret"), Ok(()));
+        assert_eq!(conf_store.device_status("phone"), Ok(DeviceStatus::Known));
+        assert!(client_manager.get_pending_approvals().is_empty());
+        assert_eq!(client_manager.get_session_ids("phone"), vec![session_id]);
+        // the client name loads the config like for a known device
+        let announced = loop {
+            match changes.recv_timeout(Duration::from_secs(1)) {
+                Ok((id, ClientStateChange::ClientName(name))) if id == session_id => break name,
+                Ok(_) => {}
+                Err(e) => panic!("No client name announced: {}", e),
+            }
+        };
+        assert_eq!(announced, "phone");
+    }
+
+    #[test]
+    fn approve_pairs_known_device() {
+        let dir = TempDir::new("approve_known");
+        let conf_store = ConfStore::new(dir.0.clone());
+        fs::write(
+            conf_store.client_file("phone", "json"),
+            r#"{"client_name":"phone","display_name":"Kept"}"#,
+        )
+        .unwrap();
+        // left over from a device that used the name before
+        fs::write(conf_store.client_file("phone", "token"), "old").unwrap();
+        let mut client_manager = ClientManager::new();
+        let pending_approval = pending("phone", "new", true);
+        let session_id = pending_session(&mut client_manager, &pending_approval);
+
+        conf_store
+            .approve_device(&mut client_manager, session_id, pending_approval)
+            .unwrap();
+
+        let config = read_config_file(conf_store.client_file("phone", "json")).unwrap();
+        assert_eq!(config.display_name.as_deref(), Some("Kept"));
+        assert_eq!(conf_store.authenticate("phone", "new"), Ok(()));
+        assert!(conf_store.authenticate("phone", "old").is_err());
+    }
+
+    #[test]
+    fn reject_device() {
+        let dir = TempDir::new("reject");
+        let conf_store = ConfStore::new(dir.0.clone());
+        let mut client_manager = ClientManager::new();
+
+        // blocking a configured device would lock it out
+        let pending_approval = pending("phone", "secret", true);
+        let session_id = pending_session(&mut client_manager, &pending_approval);
+        assert!(conf_store
+            .reject_device(&mut client_manager, session_id, pending_approval, true)
+            .is_err());
+        assert_eq!(client_manager.get_pending_approvals().len(), 1);
+        assert!(!dir.0.join(BLOCKLIST_FILE).exists());
+
+        let pending_approval = pending("intruder", "secret", false);
+        let session_id = pending_session(&mut client_manager, &pending_approval);
+        conf_store
+            .reject_device(&mut client_manager, session_id, pending_approval, true)
+            .unwrap();
+        assert_eq!(
+            conf_store.device_status("intruder"),
+            Ok(DeviceStatus::Blocked)
+        );
+        assert!(!conf_store.client_file("intruder", "json").exists());
+        assert!(!conf_store.client_file("intruder", "token").exists());
+    }
+
+    #[test]
+    fn write_config_file_leaves_no_temporary_file() {
+        let dir = TempDir::new("write_config");
+        let path = dir.0.join("phone.json");
+        let config = ClientState {
+            client_name: Some("phone".to_string()),
+            ..ClientState::default()
+        };
+        write_config_file(&path, &config).unwrap();
+        write_config_file(&path, &config).unwrap();
+        let names: Vec<_> = fs::read_dir(&dir.0)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(names, vec!["phone.json"]);
+        assert!(!has_invalid_name(&path));
+        assert!(has_invalid_name(&dir.0.join("my phone.json")));
+        assert!(!has_invalid_name(&dir.0.join(TEMPLATE_FILE)));
+    }
+}
@@ -0,0 +1,362 @@
+// owns the sockets of all connected sessions, a connection is shut down as soon as it leaves
+// the registry so that no stream outlives its session
+use crate::client_state::SessionId;
+use crate::tls;
+use rustls::ServerConfig;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+// messages waiting for a slow client, a client that falls further behind is dropped
+const QUEUE_LEN: usize = 64;
+// a client that does not take any data for this long is considered gone
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Debug)]
+pub struct ConnectionInfo {
+    pub peer_addr: SocketAddr,
+    pub connected_at: SystemTime,
+    pub encrypted: bool,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
+struct Connection {
+    // kept to drop the connection right away, writes go through the writer thread
+    socket: TcpStream,
+    outbox: Arc<Outbox>,
+    peer_addr: SocketAddr,
+    connected_at: SystemTime,
+    encrypted: bool,
+    bytes_in: Arc<AtomicU64>,
+    bytes_out: Arc<AtomicU64>,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        // the writer thread sends what is left and shuts the socket down, which also ends the
+        // receive loop reading from it
+        self.outbox.close();
+    }
+}
+
+#[derive(Default)]
+struct Outbox {
+    queue: Mutex<OutboxQueue>,
+    ready: Condvar,
+}
+
+#[derive(Default)]
+struct OutboxQueue {
+    // messages with a key carry the full state, so only the latest one per key is worth sending
+    messages: VecDeque<(Option<&'static str>, Vec<u8>)>,
+    closed: bool,
+}
+
+impl Outbox {
+    fn push(&self, key: Option<&'static str>, buf: Vec<u8>) -> Result<(), &'static str> {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.closed {
+            Err("Connection closed")?
+        }
+
+        let queued = match key {
+            Some(_) => queue.messages.iter().position(|(k, _)| *k == key),
+            None => None,
+        };
+        match queued {
+            Some(i) => queue.messages[i].1 = buf,
+            None if queue.messages.len() < QUEUE_LEN => queue.messages.push_back((key, buf)),
+            None => {
+                queue.messages.clear();
+                queue.closed = true;
+                self.ready.notify_one();
+                Err("Send queue full")?
+            }
+        }
+        self.ready.notify_one();
+        Ok(())
+    }
+
+    // blocks until there is something to send, none once closed and drained
+    fn pop(&self) -> Option<Vec<u8>> {
+        let mut queue = self.queue.lock().unwrap();
+        loop {
+            if let Some((_, buf)) = queue.messages.pop_front() {
+                return Some(buf);
+            }
+            if queue.closed {
+                return None;
+            }
+            queue = self.ready.wait(queue).unwrap();
+        }
+    }
+
+    fn close(&self) {
+        self.queue.lock().unwrap().closed = true;
+        self.ready.notify_one();
+    }
+
+    // drops everything that is still queued
+    fn abort(&self) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.messages.clear();
+        queue.closed = true;
+    }
+}
+
+type Connections = Arc<RwLock<HashMap<SessionId, Connection>>>;
+
+#[derive(Clone, Default)]
+pub struct ConnectionRegistry {
+    connections: Connections,
+}
+
+impl ConnectionRegistry {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    // takes over the socket, the returned reader is meant for the receive loop of the session
+    pub fn open(
+        &self,
+        session_id: SessionId,
+        socket: TcpStream,
+        tls_config: Option<Arc<ServerConfig>>,
+    ) -> Result<ConnectionReader, String> {
+        let peer_addr = match socket.peer_addr() {
+            Ok(addr) => normalize(addr),
+            Err(e) => Err(e.to_string())?,
+        };
+        let encrypted = tls_config.is_some();
+        if let Err(e) = socket.set_write_timeout(Some(WRITE_TIMEOUT)) {
+            Err(e.to_string())?
+        }
+
+        let (reader, writer): (Box<dyn Read + Send>, Box<dyn Write + Send + Sync>) =
+            match tls_config {
+                Some(tls_config) => {
+                    let (reader, writer) = tls::accept(tls_config, &socket)?;
+                    (Box::new(reader), Box::new(writer))
+                }
+                None => match (socket.try_clone(), socket.try_clone()) {
+                    (Ok(reader), Ok(writer)) => (Box::new(reader), Box::new(writer)),
+                    (Err(e), _) | (_, Err(e)) => Err(e.to_string())?,
+                },
+            };
+
+        let writer_socket = match socket.try_clone() {
+            Ok(socket) => socket,
+            Err(e) => Err(e.to_string())?,
+        };
+        let outbox: Arc<Outbox> = Default::default();
+        let bytes_in = Arc::new(AtomicU64::new(0));
+        let bytes_out = Arc::new(AtomicU64::new(0));
+
+        let writer_outbox = outbox.clone();
+        let writer_bytes_out = bytes_out.clone();
+        thread::spawn(move || {
+            write_loop(writer, writer_socket, writer_outbox, writer_bytes_out);
+        });
+
+        let connection = Connection {
+            socket,
+            outbox,
+            peer_addr,
+            connected_at: SystemTime::now(),
+            encrypted,
+            bytes_in: bytes_in.clone(),
+            bytes_out,
+        };
+        self.connections
+            .write()
+            .unwrap()
+            .insert(session_id, connection);
+
+        Ok(ConnectionReader { reader, bytes_in })
+    }
+
+    // queues the message for the writer thread of the session, a queued message with the same
+    // key is replaced instead. a client whose queue is full anyway is dropped
+    pub fn send(
+        &self,
+        session_id: SessionId,
+        key: Option<&'static str>,
+        buf: Vec<u8>,
+    ) -> Result<(), String> {
+        let connections = self.connections.read().unwrap();
+        let connection = match connections.get(&session_id) {
+            Some(connection) => connection,
+            None => Err("Connection not found")?,
+        };
+
+        match connection.outbox.push(key, buf) {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                // ends the receive loop of the session, which closes the connection for good
+                let _ = connection.socket.shutdown(Shutdown::Both);
+                Err(e.to_string())
+            }
+        }
+    }
+
+    // closing twice is fine, the session might be closed by the heartbeat and by its receive loop
+    pub fn close(&self, session_id: SessionId) {
+        let connection = self.connections.write().unwrap().remove(&session_id);
+        // shut down outside of the lock
+        drop(connection);
+    }
+
+    pub fn get_session_ids(&self) -> Vec<SessionId> {
+        self.connections.read().unwrap().keys().cloned().collect()
+    }
+
+    pub fn get_info(&self, session_id: SessionId) -> Option<ConnectionInfo> {
+        self.connections
+            .read()
+            .unwrap()
+            .get(&session_id)
+            .map(|connection| ConnectionInfo {
+                peer_addr: connection.peer_addr,
+                connected_at: connection.connected_at,
+                encrypted: connection.encrypted,
+                bytes_in: connection.bytes_in.load(Ordering::Relaxed),
+                bytes_out: connection.bytes_out.load(Ordering::Relaxed),
+            })
+    }
+}
+
+pub struct ConnectionReader {
+    reader: Box<dyn Read + Send>,
+    bytes_in: Arc<AtomicU64>,
+}
+
+impl Read for ConnectionReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.reader.read(buf)?;
+        self.bytes_in.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+// ipv4 clients on a dual stack listener show up as ::ffff:a.b.c.d
+fn normalize(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(IpAddr::V4(ip), addr.port()),
+            None => addr,
+        },
+        IpAddr::V4(_) => addr,
+    }
+}
+
+fn write_loop(
+    mut writer: Box<dyn Write + Send + Sync>,
+    socket: TcpStream,
+    outbox: Arc<Outbox>,
+    bytes_out: Arc<AtomicU64>,
+) {
+    while let Some(buf) = outbox.pop() {
+        // write timeouts end up here as well
+        if writer.write_all(&buf).is_err() {
+            outbox.abort();
+            break;
+        }
+        bytes_out.fetch_add(buf.len() as u64, Ordering::Relaxed);
+    }
+    let _ = socket.shutdown(Shutdown::Both);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn drain(outbox: &Outbox) -> Vec<Vec<u8>> {
+        outbox.close();
+        let mut messages = Vec::new();
+        while let Some(buf) = outbox.pop() {
+            messages.push(buf);
+        }
+        messages
+    }
+
+    #[test]
+    fn push_replaces_message_with_same_key() {
+        let outbox = Outbox::default();
+        outbox.push(Some("MuteAudio"), b"mute 1".to_vec()).unwrap();
+        outbox
+            .push(Some("AudioStream"), b"stream".to_vec())
+            .unwrap();
+        outbox.push(Some("MuteAudio"), b"mute 2".to_vec()).unwrap();
+        // the replaced message keeps its place in the queue
+        assert_eq!(drain(&outbox), vec![b"mute 2".to_vec(), b"stream".to_vec()]);
+    }
+
+    #[test]
+    fn push_keeps_messages_without_key() {
+        let outbox = Outbox::default();
+        outbox.push(None, b"error 1".to_vec()).unwrap();
+        outbox.push(None, b"error 2".to_vec()).unwrap();
+        assert_eq!(
+            drain(&outbox),
+            vec![b"error 1".to_vec(), b"error 2".to_vec()]
+        );
+    }
+
+    #[test]
+    fn push_closes_when_full() {
+        let outbox = Outbox::default();
+        for _ in 0..QUEUE_LEN {
+            outbox.push(None, b"error".to_vec()).unwrap();
+        }
+        assert!(outbox.push(None, b"error".to_vec()).is_err());
+        // the queue is dropped and the connection closed
+        assert!(outbox.push(Some("MuteAudio"), b"mute".to_vec()).is_err());
+        assert_eq!(outbox.pop(), None);
+    }
+
+    #[test]
+    fn push_replaces_when_full() {
+        let outbox = Outbox::default();
+        outbox.push(Some("MuteAudio"), b"mute 1".to_vec()).unwrap();
+        for _ in 1..QUEUE_LEN {
+            outbox.push(None, b"error".to_vec()).unwrap();
+        }
+        // replacing a queued message does not grow the queue
+        outbox.push(Some("MuteAudio"), b"mute 2".to_vec()).unwrap();
+        assert_eq!(outbox.pop(), Some(b"mute 2".to_vec()));
+    }
+
+    #[test]
+    fn pop_drains_before_closing() {
+        let outbox = Arc::new(Outbox::default());
+        let writer_outbox = outbox.clone();
+        let writer = thread::spawn(move || {
+            let mut messages = Vec::new();
+            while let Some(buf) = writer_outbox.pop() {
+                messages.push(buf);
+            }
+            messages
+        });
+        outbox.push(Some("MuteAudio"), b"mute".to_vec()).unwrap();
+        outbox.push(None, b"error".to_vec()).unwrap();
+        outbox.close();
+        assert!(outbox.push(None, b"late".to_vec()).is_err());
+        assert_eq!(
+            writer.join().unwrap(),
+            vec![b"mute".to_vec(), b"error".to_vec()]
+        );
+    }
+
+    #[test]
+    fn abort_drops_queued_messages() {
+        let outbox = Outbox::default();
+        outbox.push(None, b"error".to_vec()).unwrap();
+        outbox.abort();
+        assert_eq!(outbox.pop(), None);
+    }
+}
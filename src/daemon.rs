@@ -0,0 +1,31 @@
+// headless mode for running as a service
+use crate::client_state::ClientManager;
+use crate::connection::ConnectionRegistry;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+// time the clients get to receive the shutdown notice before the process exits anyway
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(3);
+
+// returns once the server was asked to stop via SIGTERM or SIGINT and the clients are gone
+pub fn run(client_manager: ClientManager, connections: ConnectionRegistry) -> Result<(), String> {
+    let mut signals = match Signals::new([SIGTERM, SIGINT]) {
+        Ok(signals) => signals,
+        Err(e) => Err(e.to_string())?,
+    };
+
+    signals.forever().next();
+    info!("Shutting down");
+
+    for session_id in connections.get_session_ids() {
+        client_manager.kick_client(session_id, "Server is shutting down");
+    }
+    let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+    while !connections.get_session_ids().is_empty() && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(50));
+    }
+    Ok(())
+}
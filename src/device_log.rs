@@ -0,0 +1,134 @@
+// log messages of every device in logs/<client_name>/YYYY-MM-DD.log, so that they can be looked
+// at after the fact. a day that gets too large continues in YYYY-MM-DD.1.log and so on. dumped
+// log lines go to YYYY-MM-DD.dump.log.
+use crate::logging::{LogRecord, DUMP_TARGET};
+use chrono::NaiveDate;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+#[derive(Clone)]
+pub struct DeviceLogs {
+    dir: PathBuf,
+    max_size: u64,
+    max_age: Duration,
+    // by client name and kind
+    files: Arc<Mutex<HashMap<(String, &'static str), DeviceFile>>>,
+}
+
+struct DeviceFile {
+    date: NaiveDate,
+    size: u64,
+    file: File,
+}
+
+impl DeviceLogs {
+    // fails if the directory can not be created, later write errors are dropped
+    pub fn new(dir: PathBuf, max_size: u64, max_age: Duration) -> Result<Self, String> {
+        if let Err(e) = fs::create_dir_all(&dir) {
+            Err(format!("{}: {}", dir.display(), e))?
+        }
+        Ok(DeviceLogs {
+            dir,
+            max_size,
+            max_age,
+            files: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    // records without a client name are not written, only logged in clients can send logs.
+    // the client name was checked when logging in and is safe to use as a directory name.
+    pub fn write(&self, record: &LogRecord) {
+        let client_name = match &record.client_name {
+            Some(client_name) => client_name,
+            None => return,
+        };
+        let date = record.time.date_naive();
+        let kind = match record.target.as_str() {
+            DUMP_TARGET => ".dump",
+            _ => "",
+        };
+        let key = (client_name.clone(), kind);
+
+        let mut files = self.files.lock().unwrap();
+        let rotate = match files.get(&key) {
+            Some(device_file) => device_file.date != date || device_file.size >= self.max_size,
+            None => true,
+        };
+        if rotate {
+            match self.open(client_name, kind, date) {
+                Some(device_file) => files.insert(key.clone(), device_file),
+                None => return,
+            };
+        }
+        let device_file = files.get_mut(&key).unwrap();
+
+        let mut line = format!(
+            "{} {:5} [{}] {}",
+            record.time.format("%Y-%m-%d %H:%M:%S%.3f"),
+            record.level,
+            record.session.as_deref().unwrap_or("-"),
+            record.message
+        );
+        for (key, value) in record.fields.iter() {
+            line.push_str(&format!(" {}={}", key, value));
+        }
+        line.push('\n');
+        if device_file.file.write_all(line.as_bytes()).is_ok() {
+            device_file.size += line.len() as u64;
+        }
+    }
+
+    // continues the last file of the day that still has room, e.g. after a restart
+    fn open(&self, client_name: &str, kind: &str, date: NaiveDate) -> Option<DeviceFile> {
+        let dir = self.dir.join(client_name);
+        fs::create_dir_all(&dir).ok()?;
+        self.remove_old(&dir);
+
+        let mut part = 0;
+        loop {
+            let path = dir.join(file_name(date, kind, part));
+            let size = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+            if size < self.max_size {
+                let file = OpenOptions::new()
+                    .append(true)
+                    .create(true)
+                    .open(path)
+                    .ok()?;
+                return Some(DeviceFile { date, size, file });
+            }
+            part += 1;
+        }
+    }
+
+    fn remove_old(&self, dir: &Path) {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        for entry in entries.filter_map(Result::ok) {
+            let path = entry.path();
+            if path.extension().is_none_or(|ext| ext != "log") {
+                continue;
+            }
+            let age = entry
+                .metadata()
+                .and_then(|m| m.modified())
+                .ok()
+                .and_then(|modified| SystemTime::now().duration_since(modified).ok());
+            if age.is_some_and(|age| age > self.max_age) {
+                let _ = fs::remove_file(path);
+            }
+        }
+    }
+}
+
+fn file_name(date: NaiveDate, kind: &str, part: u32) -> String {
+    match part {
+        0 => format!("{}{}.log", date.format("%Y-%m-%d"), kind),
+        _ => format!("{}{}.{}.log", date.format("%Y-%m-%d"), kind, part),
+    }
+}
@@ -1,6 +1,16 @@
+pub mod alerts;
 pub mod client_messages;
 pub mod client_state;
 pub mod conf_store;
+pub mod connection;
+pub mod daemon;
+pub mod device_log;
+pub mod logging;
+pub mod registry;
+pub mod settings;
 pub mod tcp_json;
+pub mod tls;
 pub mod ui;
+pub mod ui_socket;
+pub mod webhooks;
 pub mod zeroconf;
@@ -0,0 +1,306 @@
+// log setup, the terminal ui reads the log from a buffer since stdout belongs to it
+use crate::device_log::DeviceLogs;
+use chrono::{DateTime, Local};
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{self, IsTerminal};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use tracing::field::{Field, Visit};
+use tracing::{span, Event, Level, Subscriber};
+use tracing_subscriber::filter::{filter_fn, LevelFilter, Targets};
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::time::FormatTime;
+use tracing_subscriber::layer::{Context, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::Layer;
+
+// records kept for the terminal ui
+const BUFFER_LEN: usize = 1000;
+
+// log messages sent by the clients use this target
+pub const CLIENT_TARGET: &str = "client";
+// log lines the clients sent on request, they are kept apart from the live log
+pub const DUMP_TARGET: &str = "client_dump";
+
+#[derive(Clone, Debug)]
+pub struct LogRecord {
+    pub time: DateTime<Local>,
+    pub level: Level,
+    pub target: String,
+    // taken from the event or the session span it happened in
+    pub session: Option<String>,
+    pub client_name: Option<String>,
+    pub message: String,
+    pub fields: Vec<(String, String)>,
+}
+
+impl fmt::Display for LogRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:5} {}", self.level, self.target)?;
+        if let Some(session) = &self.session {
+            write!(f, " {}", session)?;
+        }
+        if let Some(client_name) = &self.client_name {
+            write!(f, " {}", client_name)?;
+        }
+        write!(f, ": {}", self.message)?;
+        for (key, value) in self.fields.iter() {
+            write!(f, " {}={}", key, value)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct LogBuffer {
+    records: Arc<Mutex<VecDeque<LogRecord>>>,
+    dump_records: Arc<Mutex<VecDeque<LogRecord>>>,
+}
+
+impl LogBuffer {
+    pub fn get_records(&self) -> Vec<LogRecord> {
+        self.records.lock().unwrap().iter().cloned().collect()
+    }
+
+    pub fn get_dump_records(&self) -> Vec<LogRecord> {
+        self.dump_records.lock().unwrap().iter().cloned().collect()
+    }
+
+    fn push(&self, record: LogRecord) {
+        let mut records = match record.target.as_str() {
+            DUMP_TARGET => self.dump_records.lock().unwrap(),
+            _ => self.records.lock().unwrap(),
+        };
+        if records.len() >= BUFFER_LEN {
+            records.pop_front();
+        }
+        records.push_back(record);
+    }
+}
+
+// filter is a comma separated list of levels per module, e.g.
+// "info,gecko_audio_ctrl::tcp_json=debug,client=warn"
+// the device logs get all client messages regardless of the filter, dumps are always shown
+pub fn init_buffer(filter: &str, device_logs: Option<DeviceLogs>) -> Result<LogBuffer, String> {
+    let filter = parse_filter(filter)?.with_target(DUMP_TARGET, LevelFilter::TRACE);
+    let buffer = LogBuffer::default();
+    let layer = BufferLayer {
+        buffer: buffer.clone(),
+    };
+    match tracing_subscriber::registry()
+        .with(SpanFieldsLayer.with_filter(filter_fn(|m| m.is_span())))
+        .with(device_logs.map(device_log_layer))
+        .with(layer.with_filter(filter))
+        .try_init()
+    {
+        Ok(_) => Ok(buffer),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+// logs to stderr if there is no log file. the buffer is for terminals attached to the server.
+pub fn init_writer(
+    filter: &str,
+    log_file: Option<&Path>,
+    device_logs: Option<DeviceLogs>,
+) -> Result<LogBuffer, String> {
+    let buffer_filter = parse_filter(filter)?.with_target(DUMP_TARGET, LevelFilter::TRACE);
+    let filter = parse_filter(filter)?;
+    let buffer = LogBuffer::default();
+    let buffer_layer = BufferLayer {
+        buffer: buffer.clone(),
+    };
+    let registry = tracing_subscriber::registry()
+        .with(SpanFieldsLayer.with_filter(filter_fn(|m| m.is_span())))
+        .with(device_logs.map(device_log_layer))
+        .with(buffer_layer.with_filter(buffer_filter));
+    let layer = tracing_subscriber::fmt::layer().with_timer(LocalTime);
+    let res = match log_file {
+        Some(path) => {
+            let file = match OpenOptions::new().append(true).create(true).open(path) {
+                Ok(file) => file,
+                Err(e) => Err(format!("{}: {}", path.display(), e))?,
+            };
+            registry
+                .with(
+                    layer
+                        .with_ansi(false)
+                        .with_writer(Mutex::new(file))
+                        .with_filter(filter),
+                )
+                .try_init()
+        }
+        None => registry
+            .with(
+                layer
+                    .with_ansi(io::stderr().is_terminal())
+                    .with_writer(io::stderr)
+                    .with_filter(filter),
+            )
+            .try_init(),
+    };
+    match res {
+        Ok(_) => Ok(buffer),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+// spans are let through as well, the session span carries the client name
+fn device_log_layer<S>(device_logs: DeviceLogs) -> impl Layer<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    DeviceLogLayer(device_logs).with_filter(filter_fn(|m| {
+        m.is_span() || m.target() == CLIENT_TARGET || m.target() == DUMP_TARGET
+    }))
+}
+
+fn parse_filter(filter: &str) -> Result<Targets, String> {
+    match Targets::from_str(filter) {
+        Ok(targets) => Ok(targets),
+        Err(e) => Err(format!("Invalid log filter {}: {}", filter, e)),
+    }
+}
+
+struct LocalTime;
+
+impl FormatTime for LocalTime {
+    fn format_time(&self, w: &mut Writer<'_>) -> fmt::Result {
+        write!(w, "{}", Local::now().format("%Y-%m-%d %H:%M:%S"))
+    }
+}
+
+// fields of a span, kept in its extensions so that events inside of it can use them
+struct SpanFields(Vec<(String, String)>);
+
+// sees every span, so that their fields are there for all other layers
+struct SpanFieldsLayer;
+
+impl<S> Layer<S> for SpanFieldsLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        let mut visitor = FieldVisitor::default();
+        attrs.record(&mut visitor);
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(SpanFields(visitor.fields));
+        }
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+        let mut visitor = FieldVisitor::default();
+        values.record(&mut visitor);
+        if let Some(span) = ctx.span(id) {
+            if let Some(SpanFields(fields)) = span.extensions_mut().get_mut::<SpanFields>() {
+                for (key, value) in visitor.fields {
+                    fields.retain(|(k, _)| *k != key);
+                    fields.push((key, value));
+                }
+            }
+        }
+    }
+}
+
+struct BufferLayer {
+    buffer: LogBuffer,
+}
+
+impl<S> Layer<S> for BufferLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        self.buffer.push(log_record(event, &ctx));
+    }
+}
+
+struct DeviceLogLayer(DeviceLogs);
+
+impl<S> Layer<S> for DeviceLogLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        self.0.write(&log_record(event, &ctx));
+    }
+}
+
+fn log_record<S>(event: &Event<'_>, ctx: &Context<'_, S>) -> LogRecord
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let mut visitor = FieldVisitor::default();
+    event.record(&mut visitor);
+    let mut fields = visitor.fields;
+
+    // innermost span first, fields of the event itself take precedence
+    if let Some(scope) = ctx.event_scope(event) {
+        for span in scope {
+            if let Some(SpanFields(span_fields)) = span.extensions().get::<SpanFields>() {
+                for (key, value) in span_fields.iter() {
+                    if !fields.iter().any(|(k, _)| k == key) {
+                        fields.push((key.clone(), value.clone()));
+                    }
+                }
+            }
+        }
+    }
+
+    let mut take = |name: &str| {
+        let i = fields.iter().position(|(k, _)| k == name);
+        i.map(|i| fields.remove(i).1)
+    };
+    let message = take("message").unwrap_or_default();
+    let session = take("session");
+    let client_name = take("client_name");
+    let client_fields = take("client_fields");
+
+    // key value pairs sent by a client arrive as one json object
+    if let Some(client_fields) = client_fields {
+        match serde_json::from_str::<serde_json::Map<String, Value>>(&client_fields) {
+            Ok(client_fields) => {
+                for (key, value) in client_fields {
+                    let value = match value {
+                        Value::String(value) => value,
+                        value => value.to_string(),
+                    };
+                    fields.push((key, value));
+                }
+            }
+            Err(_) => fields.push(("client_fields".to_string(), client_fields)),
+        }
+    }
+
+    LogRecord {
+        time: Local::now(),
+        level: *event.metadata().level(),
+        target: event.metadata().target().to_string(),
+        session,
+        client_name,
+        message,
+        fields,
+    }
+}
+
+#[derive(Default)]
+struct FieldVisitor {
+    fields: Vec<(String, String)>,
+}
+
+impl Visit for FieldVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.fields
+            .push((field.name().to_string(), value.to_string()));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.fields
+            .push((field.name().to_string(), format!("{:?}", value)));
+    }
+}
@@ -0,0 +1,204 @@
+// devices known from their config file or an earlier connection, also while they are offline.
+// what the config does not hold is kept in <client id>.seen next to it.
+use crate::client_state::{ClientManager, ClientState, ClientStateChange, SessionId};
+use crate::conf_store::{self, ConfStore};
+use crate::connection::ConnectionRegistry;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::net::IpAddr;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::warn;
+
+// config files that appear while running are picked up this often
+const SCAN_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct KnownDevice {
+    // taken from the config, the state of the last session once it ended
+    #[serde(skip)]
+    pub state: ClientState,
+    pub last_seen: Option<SystemTime>,
+    pub last_addr: Option<IpAddr>,
+    // of the sessions that ended, see connected_total
+    pub connected_secs: u64,
+    #[serde(skip)]
+    pub connected_since: Option<SystemTime>,
+    #[serde(skip)]
+    sessions: usize,
+}
+
+impl KnownDevice {
+    pub fn connected_total(&self) -> Duration {
+        let current = self
+            .connected_since
+            .and_then(|since| since.elapsed().ok())
+            .unwrap_or_default();
+        Duration::from_secs(self.connected_secs) + current
+    }
+}
+
+// by client name
+#[derive(Clone)]
+pub struct DeviceRegistry {
+    conf_store: ConfStore,
+    devices: Arc<RwLock<BTreeMap<String, KnownDevice>>>,
+}
+
+impl DeviceRegistry {
+    pub fn new(conf_store: ConfStore) -> Self {
+        let registry = DeviceRegistry {
+            conf_store,
+            devices: Arc::new(RwLock::new(BTreeMap::new())),
+        };
+        registry.scan();
+        registry
+    }
+
+    pub fn get_devices(&self) -> Vec<(String, KnownDevice)> {
+        self.devices
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(client_name, device)| (client_name.clone(), device.clone()))
+            .collect()
+    }
+
+    pub fn get_device(&self, client_name: &str) -> Option<KnownDevice> {
+        self.devices.read().unwrap().get(client_name).cloned()
+    }
+
+    // adds the devices of new config files and takes changed configs of offline devices. devices
+    // whose config was removed are kept until the server is restarted.
+    fn scan(&self) {
+        let entries = match fs::read_dir(self.conf_store.dir()) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        for entry in entries.filter_map(Result::ok) {
+            let path = entry.path();
+            let client_name = match path.file_stem().and_then(|stem| stem.to_str()) {
+                Some(stem) if conf_store::is_valid_client_name(stem) => stem.to_string(),
+                _ => continue,
+            };
+            let state = match conf_store::read_config_file(path) {
+                Ok(state) => state,
+                Err(_) => continue,
+            };
+
+            let mut devices = self.devices.write().unwrap();
+            let device = devices
+                .entry(client_name.clone())
+                .or_insert_with(|| self.load_seen(&client_name));
+            if device.sessions == 0 {
+                device.state = state;
+            }
+        }
+    }
+
+    fn load_seen(&self, client_name: &str) -> KnownDevice {
+        let path = self.conf_store.client_file(client_name, "seen");
+        match File::open(path) {
+            Ok(file) => serde_json::from_reader(BufReader::new(file)).unwrap_or_default(),
+            Err(_) => KnownDevice::default(),
+        }
+    }
+
+    fn save(&self, client_name: &str, device: &KnownDevice) {
+        let path = self.conf_store.client_file(client_name, "seen");
+        let res = match File::create(&path) {
+            Ok(file) => serde_json::to_writer_pretty(file, device).map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        if let Err(e) = res {
+            warn!(path = %path.display(), error = %e, "Could not store when the device was seen");
+        }
+    }
+
+    // the device was seen just now, returns it to be saved. the .seen file is only written when
+    // a session starts or ends, it sits in the watched config dir.
+    fn update<F: FnOnce(&mut KnownDevice)>(&self, client_name: &str, f: F) -> KnownDevice {
+        let mut devices = self.devices.write().unwrap();
+        let device = devices
+            .entry(client_name.to_string())
+            .or_insert_with(|| self.load_seen(client_name));
+        f(device);
+        device.last_seen = Some(SystemTime::now());
+        device.clone()
+    }
+}
+
+// follows the sessions of the devices and picks up new config files
+pub fn run(
+    registry: DeviceRegistry,
+    mut client_manager: ClientManager,
+    connections: ConnectionRegistry,
+) {
+    let change_receiver = client_manager.get_change_receiver();
+    // logged in sessions
+    let mut sessions: HashMap<SessionId, String> = HashMap::new();
+    // also while changes keep coming in, e.g. battery reports
+    let mut last_scan = Instant::now();
+
+    loop {
+        if last_scan.elapsed() >= SCAN_INTERVAL {
+            registry.scan();
+            last_scan = Instant::now();
+        }
+        let timeout = SCAN_INTERVAL.saturating_sub(last_scan.elapsed());
+        let (session_id, state_change) = match change_receiver.recv_timeout(timeout) {
+            Ok(change) => change,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+        match state_change {
+            ClientStateChange::ClientName(client_name) => {
+                // the name is set again when the config of the client changes
+                let is_new = sessions.insert(session_id, client_name.clone()).is_none();
+                if is_new {
+                    let addr = connections
+                        .get_info(session_id)
+                        .map(|connection| connection.peer_addr.ip());
+                    let device = registry.update(&client_name, |device| {
+                        device.sessions += 1;
+                        if device.connected_since.is_none() {
+                            device.connected_since = Some(SystemTime::now());
+                        }
+                        if addr.is_some() {
+                            device.last_addr = addr;
+                        }
+                    });
+                    registry.save(&client_name, &device);
+                }
+            }
+            ClientStateChange::Remove(state) => {
+                if let Some(client_name) = sessions.remove(&session_id) {
+                    let device = registry.update(&client_name, |device| {
+                        device.sessions = device.sessions.saturating_sub(1);
+                        device.state = state;
+                        if device.sessions == 0 {
+                            if let Some(since) = device.connected_since.take() {
+                                let secs = since.elapsed().unwrap_or_default().as_secs();
+                                device.connected_secs += secs;
+                            }
+                        }
+                    });
+                    registry.save(&client_name, &device);
+                }
+            }
+            // only kept in memory, the battery of a connected device changes all the time
+            ClientStateChange::BatteryLevel(_) | ClientStateChange::IsCharging(_) => {
+                if let Some(client_name) = sessions.get(&session_id) {
+                    if let Ok(state) = client_manager.get_client(session_id) {
+                        registry.update(client_name, |device| device.state = state);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,215 @@
+// server settings from an optional json file, command line flags take precedence
+use crate::client_state::DuplicatePolicy;
+use crate::webhooks::WebhookConfig;
+use serde::Deserialize;
+use std::fs::File;
+use std::io::BufReader;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+pub const OPTIONS: &str = "Options:
+    --settings FILE             read settings from a json file, relative paths in it are
+                                relative to the file
+    --port PORT                 plain tcp port (default 9000)
+    --tls-port PORT             tls port (default 9001)
+    --no-tls                    do not listen for tls connections
+    --bind ADDR                 address to listen on, can be given multiple times
+                                (default :: and 0.0.0.0)
+    --config-dir DIR            client configs, tokens, template and blocklist
+                                (default ./client_config)
+    --tls-cert FILE             certificate, generated if neither it nor the key exist
+                                (default ./tls/cert.pem)
+    --tls-key FILE              private key (default ./tls/key.pem)
+    --name NAME                 service name announced via dns-sd
+                                (default Gecko Audio Streaming)
+    --heartbeat-timeout SECS    drop clients that were silent this long (default 15)
+    --duplicate-policy POLICY   when a device logs in while it still has a session:
+                                kick_old, reject_new or allow_both (default kick_old)
+    --no-ui                     run headless, client events are logged and SIGTERM stops
+                                the server
+    --ui-socket FILE            unix socket of a headless server to attach the terminal
+                                ui to (default ./ui.sock)
+    --no-ui-socket              do not listen for terminals to attach
+    --attach                    show the terminal ui of the headless server listening on
+                                the ui socket, q detaches
+    --log-file FILE             log to a file instead of stderr when running headless
+    --log-filter FILTER         log levels per module, e.g.
+                                info,gecko_audio_ctrl::tcp_json=debug,client=warn
+                                (default info)
+    --device-log-dir DIR        log messages of each device go to
+                                DIR/<client_name>/YYYY-MM-DD.log (default ./logs)
+    --no-device-logs            do not write device log files
+    --device-log-max-mb MB      continue in a new file once a day's file is this large
+                                (default 10)
+    --device-log-keep-days DAYS remove device log files older than this (default 30)
+    --alert-rules FILE          alert rules, none if the file does not exist
+                                (default ./alerts.json)
+    --webhook URL               post all events as json to this url, can be given multiple
+                                times, adds to the webhooks of the settings file
+    -h, --help                  print this help";
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub port: u16,
+    // none disables tls
+    pub tls_port: Option<u16>,
+    pub bind: Vec<IpAddr>,
+    pub config_dir: PathBuf,
+    pub tls_cert: PathBuf,
+    pub tls_key: PathBuf,
+    pub announce_name: String,
+    pub heartbeat_timeout_secs: u64,
+    pub duplicate_policy: DuplicatePolicy,
+    pub ui: bool,
+    // none disables attaching to a headless server
+    pub ui_socket: Option<PathBuf>,
+    // not a setting of the server, only given as a flag
+    #[serde(skip)]
+    pub attach: bool,
+    // stderr if none
+    pub log_file: Option<PathBuf>,
+    pub log_filter: String,
+    // none disables the device log files
+    pub device_log_dir: Option<PathBuf>,
+    pub device_log_max_mb: u64,
+    pub device_log_keep_days: u64,
+    pub alert_rules: PathBuf,
+    pub webhooks: Vec<WebhookConfig>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            port: 9000,
+            tls_port: Some(9001),
+            bind: vec![
+                IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+                IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            ],
+            config_dir: PathBuf::from("./client_config"),
+            tls_cert: PathBuf::from("./tls/cert.pem"),
+            tls_key: PathBuf::from("./tls/key.pem"),
+            announce_name: "Gecko Audio Streaming".to_string(),
+            heartbeat_timeout_secs: 15,
+            duplicate_policy: DuplicatePolicy::KickOld,
+            ui: true,
+            ui_socket: Some(PathBuf::from("./ui.sock")),
+            attach: false,
+            log_file: None,
+            log_filter: "info".to_string(),
+            device_log_dir: Some(PathBuf::from("./logs")),
+            device_log_max_mb: 10,
+            device_log_keep_days: 30,
+            alert_rules: PathBuf::from("./alerts.json"),
+            webhooks: Vec::new(),
+        }
+    }
+}
+
+impl Settings {
+    pub fn heartbeat_timeout(&self) -> Duration {
+        Duration::from_secs(self.heartbeat_timeout_secs)
+    }
+
+    pub fn device_log_max_size(&self) -> u64 {
+        self.device_log_max_mb * 1024 * 1024
+    }
+
+    pub fn device_log_max_age(&self) -> Duration {
+        Duration::from_secs(self.device_log_keep_days * 24 * 60 * 60)
+    }
+
+    // none if only the usage was asked for
+    pub fn from_args(args: &[String]) -> Result<Option<Settings>, String> {
+        // the file is the base for all other flags, no matter where it is given
+        let mut settings = match args.iter().position(|arg| arg == "--settings") {
+            Some(i) => match args.get(i + 1) {
+                Some(path) => Settings::from_file(Path::new(path))?,
+                None => Err("--settings needs a value")?,
+            },
+            None => Settings::default(),
+        };
+
+        let mut bind = Vec::new();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(None),
+                "--no-tls" => settings.tls_port = None,
+                "--no-ui" => settings.ui = false,
+                "--no-ui-socket" => settings.ui_socket = None,
+                "--attach" => settings.attach = true,
+                "--no-device-logs" => settings.device_log_dir = None,
+                "--settings" => {
+                    args.next();
+                }
+                "--port" => settings.port = parse(arg, args.next())?,
+                "--tls-port" => settings.tls_port = Some(parse(arg, args.next())?),
+                "--bind" => bind.push(parse(arg, args.next())?),
+                "--config-dir" => settings.config_dir = parse(arg, args.next())?,
+                "--tls-cert" => settings.tls_cert = parse(arg, args.next())?,
+                "--tls-key" => settings.tls_key = parse(arg, args.next())?,
+                "--name" => settings.announce_name = parse(arg, args.next())?,
+                "--log-filter" => settings.log_filter = parse(arg, args.next())?,
+                "--log-file" => settings.log_file = Some(parse(arg, args.next())?),
+                "--ui-socket" => settings.ui_socket = Some(parse(arg, args.next())?),
+                "--heartbeat-timeout" => settings.heartbeat_timeout_secs = parse(arg, args.next())?,
+                "--duplicate-policy" => settings.duplicate_policy = parse(arg, args.next())?,
+                "--device-log-dir" => settings.device_log_dir = Some(parse(arg, args.next())?),
+                "--alert-rules" => settings.alert_rules = parse(arg, args.next())?,
+                "--webhook" => settings
+                    .webhooks
+                    .push(WebhookConfig::new(parse(arg, args.next())?)),
+                "--device-log-max-mb" => settings.device_log_max_mb = parse(arg, args.next())?,
+                "--device-log-keep-days" => {
+                    settings.device_log_keep_days = parse(arg, args.next())?
+                }
+                _ => Err(format!("Unknown option {}", arg))?,
+            }
+        }
+        if !bind.is_empty() {
+            settings.bind = bind;
+        }
+
+        if settings.heartbeat_timeout_secs == 0 {
+            Err("The heartbeat timeout has to be at least one second")?
+        }
+        if settings.device_log_max_mb == 0 {
+            Err("Device log files have to be allowed at least one MB")?
+        }
+        Ok(Some(settings))
+    }
+
+    // paths in the file are relative to it, so that an instance can be started from anywhere
+    pub fn from_file(path: &Path) -> Result<Settings, String> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) => Err(format!("{}: {}", path.display(), e))?,
+        };
+        let mut settings: Settings = match serde_json::from_reader(BufReader::new(file)) {
+            Ok(settings) => settings,
+            Err(e) => Err(format!("{}: {}", path.display(), e))?,
+        };
+
+        if let Some(base) = path.parent() {
+            settings.config_dir = base.join(&settings.config_dir);
+            settings.tls_cert = base.join(&settings.tls_cert);
+            settings.tls_key = base.join(&settings.tls_key);
+            settings.log_file = settings.log_file.map(|log_file| base.join(log_file));
+            settings.ui_socket = settings.ui_socket.map(|ui_socket| base.join(ui_socket));
+            settings.device_log_dir = settings.device_log_dir.map(|dir| base.join(dir));
+            settings.alert_rules = base.join(&settings.alert_rules);
+        }
+        Ok(settings)
+    }
+}
+
+fn parse<T: std::str::FromStr>(flag: &str, value: Option<&String>) -> Result<T, String> {
+    match value.map(|value| value.parse()) {
+        Some(Ok(value)) => Ok(value),
+        Some(Err(_)) => Err(format!("Invalid value for {}", flag)),
+        None => Err(format!("{} needs a value", flag)),
+    }
+}
@@ -1,15 +1,28 @@
 use crate::client_messages::{
-    AudioStream, BatLogInterval, BatteryLevel, DisplayName, Hello, LogMsg, MuteAudio, TransmitAudio,
+    Ack, AudioStream, BatLogInterval, BatteryLevel, ClientLogLevel, DisplayName, ErrorCode,
+    ErrorMsg, Hello, LogDump, LogLevel, LogMsg, MuteAudio, Nack, TransmitAudio, Welcome, CAP_ACK,
+    CAP_LOG, CAP_PING, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, SERVER_CAPABILITIES,
 };
-use crate::client_state::{ClientManager, ClientStateChange};
+use crate::client_state::{
+    ClientManager, ClientStateChange, CommandState, PendingApproval, SessionId,
+};
+use crate::conf_store::{ConfStore, DeviceStatus};
+use crate::connection::ConnectionRegistry;
+use crate::logging::{CLIENT_TARGET, DUMP_TARGET};
+use chrono::{DateTime, Local};
+use rustls::ServerConfig;
 use serde::{Deserialize, Serialize};
-use serde_json::Deserializer;
-use std::collections::HashMap;
-use std::net::{SocketAddr, TcpListener, TcpStream};
+use serde_json::{Deserializer, Value};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
 use std::sync::mpsc::channel;
-use std::sync::{Arc, RwLock};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use std::{io, thread};
-use std::io::Write;
+use tracing::{debug, event, field, info, info_span, warn, Level, Span};
+
+// time a client has to acknowledge a command before it is shown as timed out
+const ACK_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[derive(Deserialize, Clone, Debug)]
 #[serde(tag = "type")]
@@ -18,127 +31,295 @@ enum MessageToServer {
     Ping,
     BatteryLevel(BatteryLevel),
     LogMsg(LogMsg),
+    LogDump(LogDump),
     DisplayName(DisplayName),
     AudioStream(AudioStream),
     MuteAudio(MuteAudio),
     TransmitAudio(TransmitAudio),
+    Ack(Ack),
+    Nack(Nack),
+    #[serde(other)]
+    Unknown,
 }
 
 #[derive(Serialize, Clone, Debug)]
 #[serde(tag = "type")]
 enum MessagesFromServer {
-    //Ping,
+    Ping,
     DisplayName(DisplayName),
     AudioStream(AudioStream),
     MuteAudio(MuteAudio),
     TransmitAudio(TransmitAudio),
     BatLogInterval(BatLogInterval),
+    LogLevel(ClientLogLevel),
+    RequestLogDump,
+    Welcome(Welcome),
+    Error(ErrorMsg),
+}
+
+impl MessagesFromServer {
+    fn message_type(&self) -> &'static str {
+        match self {
+            MessagesFromServer::Ping => "Ping",
+            MessagesFromServer::DisplayName(_) => "DisplayName",
+            MessagesFromServer::AudioStream(_) => "AudioStream",
+            MessagesFromServer::MuteAudio(_) => "MuteAudio",
+            MessagesFromServer::TransmitAudio(_) => "TransmitAudio",
+            MessagesFromServer::BatLogInterval(_) => "BatLogInterval",
+            MessagesFromServer::LogLevel(_) => "LogLevel",
+            MessagesFromServer::RequestLogDump => "RequestLogDump",
+            MessagesFromServer::Welcome(_) => "Welcome",
+            MessagesFromServer::Error(_) => "Error",
+        }
+    }
+
+    // messages that carry the complete state, a newer one makes a queued one obsolete
+    fn coalesce_key(&self) -> Option<&'static str> {
+        match self {
+            MessagesFromServer::Welcome(_) | MessagesFromServer::Error(_) => None,
+            _ => Some(self.message_type()),
+        }
+    }
 }
 
-pub fn run(port: u16, client_manager: ClientManager) {
-    //let listener_v4 = TcpListener::bind(format!("0.0.0.0:{}", port)).unwrap();
-    let listener_v6 = TcpListener::bind(format!("[::]:{}", port)).unwrap();
+// clients that support acks get an id with every command
+#[derive(Serialize)]
+struct Envelope<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<u64>,
+    #[serde(flatten)]
+    message: &'a MessagesFromServer,
+}
+
+pub struct Listeners {
+    listeners: Vec<(TcpListener, Option<Arc<ServerConfig>>)>,
+    pub report: ListenReport,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ListenReport {
+    pub bound: Vec<SocketAddr>,
+    pub failed: Vec<(SocketAddr, String)>,
+}
+
+// every address gets a plain tcp listener on port, which stays available for clients that do not
+// support tls, and a tls listener if configured. an address that can not be bound does not stop
+// the others from working
+pub fn listen(
+    bind_addrs: &[IpAddr],
+    port: u16,
+    tls: Option<(u16, Arc<ServerConfig>)>,
+) -> Listeners {
+    // a v6 wildcard accepts ipv4 as well (regardless of bindv6only), unless ipv4 addresses are
+    // listed explicitly and would collide with it
+    let only_v6 = bind_addrs.iter().any(IpAddr::is_ipv4);
+
+    let mut ports = vec![(port, None)];
+    if let Some((tls_port, tls_config)) = tls {
+        ports.push((tls_port, Some(tls_config)));
+    }
+
+    let mut listeners = Listeners {
+        listeners: Vec::new(),
+        report: Default::default(),
+    };
+    for (port, tls_config) in ports {
+        for ip in bind_addrs {
+            let addr = SocketAddr::new(*ip, port);
+            match bind(addr, only_v6) {
+                Ok(listener) => {
+                    listeners.listeners.push((listener, tls_config.clone()));
+                    listeners.report.bound.push(addr);
+                }
+                Err(e) => listeners.report.failed.push((addr, e.to_string())),
+            }
+        }
+    }
+    listeners
+}
 
-    let send_streams: Arc<RwLock<HashMap<SocketAddr, TcpStream>>> = Default::default();
+fn bind(addr: SocketAddr, only_v6: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(only_v6)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(128)?;
+    Ok(socket.into())
+}
 
-    let state_change_send_streams = send_streams.clone();
+pub fn run(
+    listeners: Listeners,
+    heartbeat_timeout: Duration,
+    client_manager: ClientManager,
+    connections: ConnectionRegistry,
+    conf_store: ConfStore,
+) {
+    let state_change_connections = connections.clone();
     let state_change_client_manager = client_manager.clone();
     thread::spawn(move || {
-        handle_client_state_change(state_change_send_streams, state_change_client_manager);
+        handle_client_state_change(state_change_connections, state_change_client_manager);
     });
 
-    let (tx, rx) = channel();
-
-    //let v4_tx = tx.clone();
-    //thread::spawn(move || {
-    //    for stream in listener_v4.incoming() {
-    //        v4_tx.send(stream).unwrap();
-    //    }
-    //});
-
-    let v6_tx = tx.clone();
+    let heartbeat_connections = connections.clone();
+    let heartbeat_client_manager = client_manager.clone();
     thread::spawn(move || {
-        for stream in listener_v6.incoming() {
-            v6_tx.send(stream).unwrap();
-        }
+        heartbeat(
+            heartbeat_connections,
+            heartbeat_client_manager,
+            heartbeat_timeout,
+        );
     });
 
+    let command_client_manager = client_manager.clone();
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(1));
+        command_client_manager.expire_commands(ACK_TIMEOUT);
+    });
+
+    let (tx, rx) = channel();
+
+    for (listener, tls_config) in listeners.listeners {
+        let listener_tx = tx.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                listener_tx.send((stream, tls_config.clone())).unwrap();
+            }
+        });
+    }
+
     loop {
-        let stream = rx.recv().unwrap();
+        let (stream, tls_config) = rx.recv().unwrap();
         let client_manager = client_manager.clone();
-        let send_streams = send_streams.clone();
+        let connections = connections.clone();
+        let conf_store = conf_store.clone();
         thread::spawn(move || {
-            if let Err(_e) = handle_client(send_streams, stream, client_manager.clone()) {
-                // log e
+            if let Err(e) = handle_client(
+                connections,
+                conf_store,
+                stream,
+                tls_config,
+                client_manager.clone(),
+            ) {
+                warn!(error = %e, "Could not accept connection");
             }
         });
     }
 }
 
 fn handle_client(
-    send_streams: Arc<RwLock<HashMap<SocketAddr, TcpStream>>>,
+    connections: ConnectionRegistry,
+    conf_store: ConfStore,
     stream: io::Result<TcpStream>,
+    tls_config: Option<Arc<ServerConfig>>,
     mut client_manager: ClientManager,
 ) -> Result<(), String> {
-    let session_id = match stream.as_ref() {
-        Ok(stream) => match stream.peer_addr() {
-            Ok(addr) => addr.clone(),
-            Err(e) => Err(e.to_string())?,
-        },
-        Err(e) => Err(e.to_string())?,
-    };
-
-    let receive_stream = match stream {
+    let socket = match stream {
         Ok(stream) => stream,
         Err(e) => Err(e.to_string())?,
     };
 
-    let send_stream = match receive_stream.try_clone() {
-        Ok(stream) => stream,
-        Err(e) => Err(e.to_string())?,
-    };
+    let session_id = client_manager.new_client();
+    // everything logged while handling the session carries its id and, once known, client name
+    let span = info_span!(
+        "session",
+        session = %session_id,
+        client_name = field::Empty
+    );
+    let _span = span.enter();
 
-    {
-        // new scope so, that write lock drops after insertion
-        let mut send_streams = send_streams.write().unwrap();
-        send_streams.insert(session_id, send_stream);
+    let receive_stream = match connections.open(session_id, socket, tls_config) {
+        Ok(reader) => reader,
+        Err(e) => {
+            client_manager.rm_client(session_id);
+            Err(e)?
+        }
+    };
+    if let Some(connection) = connections.get_info(session_id) {
+        info!(
+            peer_addr = %connection.peer_addr,
+            encrypted = connection.encrypted,
+            "Connected"
+        );
     }
 
-    client_manager.new_client(session_id);
-
-    let json_stream = Deserializer::from_reader(receive_stream).into_iter::<MessageToServer>();
+    // parse into a json value first, so that a message we do not understand does not end the stream
+    let json_stream = Deserializer::from_reader(receive_stream).into_iter::<Value>();
 
     for message in json_stream {
-        let mes = match message {
-            Ok(mes) => mes,
-            Err(_e) => {
-                //log e
+        let value = match message {
+            Ok(value) => value,
+            Err(e) if e.is_io() => break, // connection closed
+            Err(e) => {
+                warn!(error = %e, "Invalid json");
+                let error = ErrorMsg::new(ErrorCode::InvalidJson, &e.to_string());
+                send_error(&connections, session_id, error);
                 break;
             }
         };
-        let res = handle_client_message_received(session_id, &mut client_manager, mes);
-        if let Err(e) = res {
-            println!("{}, disconnecting client!", e);
-            break;
+        client_manager.touch_client(session_id);
+
+        let message_type = value.get("type").and_then(Value::as_str).map(String::from);
+        let res = match serde_json::from_value(value) {
+            Ok(mes) => handle_client_message_received(
+                &connections,
+                &conf_store,
+                session_id,
+                &mut client_manager,
+                mes,
+            ),
+            Err(e) => Err(ErrorMsg::new(ErrorCode::InvalidMessage, &e.to_string())),
+        };
+        if let Err(mut error) = res {
+            let is_fatal = error.code.is_fatal();
+            warn!(
+                code = ?error.code,
+                message_type = ?message_type,
+                "{}",
+                error.message
+            );
+            error.message_type = message_type;
+            send_error(&connections, session_id, error);
+            if is_fatal {
+                break;
+            }
         }
     }
+    connections.close(session_id);
     client_manager.rm_client(session_id);
+    info!("Disconnected");
     Ok(())
 }
 
 fn handle_client_message_received(
-    session_id: SocketAddr,
+    connections: &ConnectionRegistry,
+    conf_store: &ConfStore,
+    session_id: SessionId,
     client_manager: &mut ClientManager,
     message: MessageToServer,
-) -> Result<(), &'static str> {
+) -> Result<(), ErrorMsg> {
+    let is_identified = client_manager.get_client(session_id)?.client_name.is_some();
+
     match message {
-        MessageToServer::Hello(hello) => client_manager
-            .set_client_property(session_id, ClientStateChange::ClientName(hello.client_name)),
-        MessageToServer::Ping => {
-            println!("Ping from {}", session_id);
-            Ok(())
+        MessageToServer::Hello(hello) => {
+            handle_hello(connections, conf_store, session_id, client_manager, hello)
         }
+        MessageToServer::Ping => Ok(()), // last seen is already updated
+        MessageToServer::Unknown => Err(ErrorMsg::new(
+            ErrorCode::UnknownMessageType,
+            "Unknown message type",
+        )),
+        _ if !is_identified => Err(ErrorMsg::new(
+            ErrorCode::NotIdentified,
+            "Hello has to be sent first",
+        )),
         MessageToServer::BatteryLevel(battery_level) => {
+            client_manager.record_battery(
+                session_id,
+                battery_level.level,
+                battery_level.is_charging,
+            );
             client_manager.set_client_property(
                 session_id,
                 ClientStateChange::BatteryLevel(battery_level.level),
@@ -146,16 +327,33 @@ fn handle_client_message_received(
             client_manager.set_client_property(
                 session_id,
                 ClientStateChange::IsCharging(battery_level.is_charging),
-            )
+            )?;
+            Ok(())
         }
         MessageToServer::LogMsg(log_msg) => {
-            println!("LOG: '{}': {}", session_id, log_msg.message);
+            let client_name = client_manager.get_client(session_id)?.client_name;
+            log_client_message(log_msg, false, client_name.as_deref());
+            Ok(())
+        }
+        MessageToServer::LogDump(log_dump) => {
+            let client_name = client_manager.get_client(session_id)?.client_name;
+            info!(
+                client_name = client_name.as_deref(),
+                lines = log_dump.lines.len(),
+                "Log dump received"
+            );
+            for log_msg in log_dump.lines {
+                log_client_message(log_msg, true, client_name.as_deref());
+            }
+            Ok(())
+        }
+        MessageToServer::DisplayName(display_name) => {
+            client_manager.set_client_property(
+                session_id,
+                ClientStateChange::DisplayName(display_name.display_name),
+            )?;
             Ok(())
         }
-        MessageToServer::DisplayName(display_name) => client_manager.set_client_property(
-            session_id,
-            ClientStateChange::DisplayName(display_name.display_name),
-        ),
         MessageToServer::AudioStream(audio_stream) => {
             client_manager.set_client_property(
                 session_id,
@@ -172,7 +370,8 @@ fn handle_client_message_received(
             client_manager.set_client_property(
                 session_id,
                 ClientStateChange::SendRepairPort(audio_stream.send_repair_port),
-            )
+            )?;
+            Ok(())
         }
         MessageToServer::MuteAudio(mute_audio) => {
             client_manager.set_client_property(
@@ -182,7 +381,17 @@ fn handle_client_message_received(
             client_manager.set_client_property(
                 session_id,
                 ClientStateChange::RecvMute(mute_audio.recv_mute),
-            )
+            )?;
+            Ok(())
+        }
+        MessageToServer::Ack(ack) => {
+            client_manager.set_command_state(session_id, ack.id, CommandState::Acked)?;
+            Ok(())
+        }
+        MessageToServer::Nack(nack) => {
+            let state = CommandState::Nacked(nack.reason);
+            client_manager.set_command_state(session_id, nack.id, state)?;
+            Ok(())
         }
         MessageToServer::TransmitAudio(transmit_audio) => {
             client_manager.set_client_property(
@@ -192,29 +401,239 @@ fn handle_client_message_received(
             client_manager.set_client_property(
                 session_id,
                 ClientStateChange::RecvAudio(transmit_audio.recv_audio),
+            )?;
+            Ok(())
+        }
+    }
+}
+
+// the level and target of an event have to be known at compile time. dumped lines are kept apart
+// from the live log. the client name is given explicitly, the session span only has it for
+// devices that were known when they logged in, not for those approved afterwards.
+fn log_client_message(log_msg: LogMsg, dumped: bool, client_name: Option<&str>) {
+    let client_time = log_msg
+        .timestamp
+        .and_then(DateTime::from_timestamp_millis)
+        .map(|time| time.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S%.3f"));
+    // tracing needs static field names, the log layers unpack them again
+    let client_fields = if log_msg.fields.is_empty() {
+        None
+    } else {
+        serde_json::to_string(&log_msg.fields).ok()
+    };
+    macro_rules! client_event {
+        ($target:expr, $level:expr) => {
+            event!(
+                target: $target,
+                $level,
+                client_name,
+                tag = log_msg.tag.as_deref(),
+                client_time = client_time.as_ref().map(field::display),
+                client_fields = client_fields.as_deref(),
+                "{}",
+                log_msg.message
             )
+        };
+        ($level:expr) => {
+            if dumped {
+                client_event!(DUMP_TARGET, $level)
+            } else {
+                client_event!(CLIENT_TARGET, $level)
+            }
+        };
+    }
+    match log_msg.level.unwrap_or(LogLevel::Info) {
+        LogLevel::Error => client_event!(Level::ERROR),
+        LogLevel::Warn => client_event!(Level::WARN),
+        LogLevel::Info => client_event!(Level::INFO),
+        LogLevel::Debug => client_event!(Level::DEBUG),
+        LogLevel::Trace => client_event!(Level::TRACE),
+    }
+}
+
+fn handle_hello(
+    connections: &ConnectionRegistry,
+    conf_store: &ConfStore,
+    session_id: SessionId,
+    client_manager: &mut ClientManager,
+    hello: Hello,
+) -> Result<(), ErrorMsg> {
+    // clients without a protocol version predate the negotiation and get no reply
+    let client_version = hello.protocol_version.unwrap_or(1);
+    let protocol_version = client_version.min(PROTOCOL_VERSION);
+
+    if protocol_version < MIN_PROTOCOL_VERSION {
+        let message = format!(
+            "Protocol version {} is not supported, the server requires at least version {}",
+            client_version, MIN_PROTOCOL_VERSION
+        );
+        return Err(ErrorMsg::new(ErrorCode::IncompatibleVersion, &message));
+    }
+
+    let capabilities: Vec<String> = hello
+        .capabilities
+        .into_iter()
+        .filter(|c| SERVER_CAPABILITIES.contains(&c.as_str()))
+        .collect();
+    client_manager.set_protocol(session_id, protocol_version, capabilities.clone())?;
+
+    if hello.protocol_version.is_some() {
+        let msg = MessagesFromServer::Welcome(Welcome {
+            protocol_version,
+            capabilities,
+        });
+        if let Err(e) = send_message(connections, session_id, &msg) {
+            warn!(error = %e, "Could not send welcome");
+        }
+    }
+
+    // has to happen before the client name is set, which loads and persists its config
+    let token = hello.token.unwrap_or_default();
+    let device_status = match conf_store.device_status(&hello.client_name) {
+        Ok(device_status) => device_status,
+        Err(e) => return Err(ErrorMsg::new(ErrorCode::Unauthorized, &e)),
+    };
+    match device_status {
+        DeviceStatus::Blocked => Err(ErrorMsg::new(ErrorCode::Unauthorized, "Device is blocked")),
+        DeviceStatus::Unknown | DeviceStatus::Unpaired if token.is_empty() => {
+            Err(ErrorMsg::new(ErrorCode::Unauthorized, "No token sent"))
+        }
+        // any phone could claim the name of a known device, so its first token needs approval
+        // as well
+        DeviceStatus::Unknown | DeviceStatus::Unpaired => {
+            let pairing = device_status == DeviceStatus::Unpaired;
+            let pending_approval = PendingApproval {
+                client_name: hello.client_name,
+                token,
+                since: Instant::now(),
+                pairing,
+            };
+            info!(
+                client_name = %pending_approval.client_name,
+                pairing,
+                "Waiting for approval"
+            );
+            client_manager.set_pending_approval(session_id, Some(pending_approval))?;
+            Err(ErrorMsg::new(
+                ErrorCode::PendingApproval,
+                if pairing {
+                    "Device is not paired, waiting for the operator to approve its token"
+                } else {
+                    "Unknown device, waiting for the operator to approve it"
+                },
+            ))
+        }
+        DeviceStatus::Known => {
+            if let Err(e) = conf_store.authenticate(&hello.client_name, &token) {
+                return Err(ErrorMsg::new(ErrorCode::Unauthorized, &e));
+            }
+            if let Err(e) = client_manager.claim_device(session_id, &hello.client_name) {
+                return Err(ErrorMsg::new(ErrorCode::AlreadyConnected, e));
+            }
+            Span::current().record("client_name", field::display(&hello.client_name));
+            info!("Logged in");
+            client_manager.set_client_property(
+                session_id,
+                ClientStateChange::ClientName(hello.client_name),
+            )?;
+            Ok(())
         }
     }
 }
 
-fn handle_client_state_change(
-    send_streams: Arc<RwLock<HashMap<SocketAddr, TcpStream>>>,
+fn heartbeat(
+    connections: ConnectionRegistry,
     mut client_manager: ClientManager,
+    timeout: Duration,
 ) {
+    loop {
+        thread::sleep(timeout / 3);
+
+        for session_id in client_manager.get_silent_clients(timeout) {
+            info!(session = %session_id, "Dropping silent client");
+            // closing the connection also ends the receive loop in handle_client
+            connections.close(session_id);
+            client_manager.rm_client(session_id);
+        }
+
+        for session_id in connections.get_session_ids() {
+            // older clients do not know the ping message and would disconnect
+            if !client_manager.has_capability(session_id, CAP_PING) {
+                continue;
+            }
+            if let Err(e) = send_message(&connections, session_id, &MessagesFromServer::Ping) {
+                debug!(session = %session_id, error = %e, "Could not send ping");
+            }
+        }
+    }
+}
+
+// errors are sent regardless of the negotiated capabilities, a client that receives one
+// misbehaved and would have been disconnected by older servers anyway
+fn send_error(connections: &ConnectionRegistry, session_id: SessionId, error: ErrorMsg) {
+    if let Err(e) = send_message(connections, session_id, &MessagesFromServer::Error(error)) {
+        debug!(session = %session_id, error = %e, "Could not send error");
+    }
+}
+
+fn send_message(
+    connections: &ConnectionRegistry,
+    session_id: SessionId,
+    msg: &MessagesFromServer,
+) -> Result<(), String> {
+    send_message_with_id(connections, session_id, None, msg)
+}
+
+fn send_message_with_id(
+    connections: &ConnectionRegistry,
+    session_id: SessionId,
+    id: Option<u64>,
+    message: &MessagesFromServer,
+) -> Result<(), String> {
+    let mut buf = match serde_json::to_vec(&Envelope { id, message }) {
+        Ok(buf) => buf,
+        Err(e) => Err(e.to_string())?,
+    };
+    buf.push(b'\n');
+
+    connections.send(session_id, message.coalesce_key(), buf)
+}
+
+fn handle_client_state_change(connections: ConnectionRegistry, mut client_manager: ClientManager) {
     let client_state_change_receiver = client_manager.get_change_receiver();
     loop {
         let (session_id, event) = client_state_change_receiver.recv().unwrap();
+
+        // lifecycle events first, the client state is already gone after a remove
+        match event {
+            ClientStateChange::Remove(_) => {
+                connections.close(session_id);
+                continue;
+            }
+            ClientStateChange::Add => continue,
+            ClientStateChange::Kick(reason) => {
+                info!(session = %session_id, reason = %reason, "Kicking client");
+                send_error(
+                    &connections,
+                    session_id,
+                    ErrorMsg::new(ErrorCode::Kicked, &reason),
+                );
+                // the receive loop in handle_client ends and removes the client
+                connections.close(session_id);
+                continue;
+            }
+            _ => {}
+        }
+
         let state = match client_manager.get_client(session_id) {
             Ok(state) => state,
             Err(_) => continue, // probably disconnected
         };
 
         let msg = match event {
-            ClientStateChange::Remove(_) => {
-                send_streams.write().unwrap().remove(&session_id);
+            ClientStateChange::Remove(_) | ClientStateChange::Add | ClientStateChange::Kick(_) => {
                 None
             }
-            ClientStateChange::Add => None,
 
             ClientStateChange::ClientName(_) => None,
             ClientStateChange::BatteryLevel(_) => None,
@@ -287,19 +706,40 @@ fn handle_client_state_change(
                     battery_log_interval_secs,
                 }))
             }
-        };
-
-        if let Some(msg) = msg {
-            let mut send_streams = send_streams.write().unwrap();
+            // kept in the config until the client supports it
+            ClientStateChange::LogLevel(_)
+                if !client_manager.has_capability(session_id, CAP_LOG) =>
             {
-                let send_stream = send_streams.get_mut(&session_id).unwrap();
-
-                if let Err(_e) = serde_json::to_writer(send_stream, &msg) {
-                    // log e
-                };
+                None
+            }
+            ClientStateChange::LogLevel(log_level) => {
+                Some(MessagesFromServer::LogLevel(ClientLogLevel { log_level }))
             }
+            ClientStateChange::RequestLogDump
+                if !client_manager.has_capability(session_id, CAP_LOG) =>
             {
-                send_streams.get_mut(&session_id).unwrap().write(b"\n").unwrap();
+                warn!(session = %session_id, "Client does not support log dumps");
+                None
+            }
+            ClientStateChange::RequestLogDump => Some(MessagesFromServer::RequestLogDump),
+        };
+
+        if let Some(msg) = msg {
+            let id = if client_manager.has_capability(session_id, CAP_ACK) {
+                match client_manager.add_command(session_id, msg.message_type()) {
+                    Ok(id) => Some(id),
+                    Err(_) => continue, // probably disconnected
+                }
+            } else {
+                None
+            };
+            if let Err(e) = send_message_with_id(&connections, session_id, id, &msg) {
+                warn!(
+                    session = %session_id,
+                    message_type = msg.message_type(),
+                    error = %e,
+                    "Could not send command"
+                );
             }
         }
     }
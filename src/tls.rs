@@ -0,0 +1,190 @@
+// tls for the tcp_json control channel, clients pin the server certificate by its fingerprint
+use rustls::{Certificate, PrivateKey, ServerConfig, ServerConnection};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+pub struct TlsIdentity {
+    pub config: Arc<ServerConfig>,
+    // sha256 of the der encoded certificate as colon separated hex
+    pub fingerprint: String,
+}
+
+pub fn load_or_generate(cert_path: &Path, key_path: &Path) -> Result<TlsIdentity, String> {
+    if !cert_path.exists() && !key_path.exists() {
+        generate(cert_path, key_path)?;
+    }
+
+    let certs = match read_pem(cert_path) {
+        Ok(items) => items
+            .into_iter()
+            .filter_map(|item| match item {
+                rustls_pemfile::Item::X509Certificate(der) => Some(Certificate(der)),
+                _ => None,
+            })
+            .collect::<Vec<Certificate>>(),
+        Err(e) => Err(e.to_string())?,
+    };
+    let key = match read_pem(key_path) {
+        Ok(items) => items.into_iter().find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(der)
+            | rustls_pemfile::Item::RSAKey(der)
+            | rustls_pemfile::Item::ECKey(der) => Some(PrivateKey(der)),
+            _ => None,
+        }),
+        Err(e) => Err(e.to_string())?,
+    };
+
+    let fingerprint = match certs.first() {
+        Some(cert) => fingerprint(&cert.0),
+        None => Err("No certificate found")?,
+    };
+    let key = match key {
+        Some(key) => key,
+        None => Err("No private key found")?,
+    };
+
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key);
+    match config {
+        Ok(config) => Ok(TlsIdentity {
+            config: Arc::new(config),
+            fingerprint,
+        }),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn generate(cert_path: &Path, key_path: &Path) -> Result<(), String> {
+    let cert = match rcgen::generate_simple_self_signed(vec!["gecko-audio-ctrl".to_string()]) {
+        Ok(cert) => cert,
+        Err(e) => Err(e.to_string())?,
+    };
+    let cert_pem = match cert.serialize_pem() {
+        Ok(pem) => pem,
+        Err(e) => Err(e.to_string())?,
+    };
+
+    for path in [cert_path, key_path].iter() {
+        if let Some(dir) = path.parent() {
+            if let Err(e) = fs::create_dir_all(dir) {
+                Err(e.to_string())?
+            }
+        }
+    }
+
+    let key_file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(key_path);
+    let res = key_file
+        .and_then(|mut file| file.write_all(cert.serialize_private_key_pem().as_bytes()))
+        .and_then(|_| fs::write(cert_path, cert_pem));
+    match res {
+        Ok(_) => Ok(()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn read_pem(path: &Path) -> io::Result<Vec<rustls_pemfile::Item>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    rustls_pemfile::read_all(&mut reader)
+}
+
+fn fingerprint(der: &[u8]) -> String {
+    Sha256::digest(der)
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<String>>()
+        .join(":")
+}
+
+// reader and writer share the tls state, the socket is only read outside of the lock so that
+// sending is not blocked by a client that has nothing to say
+pub fn accept(
+    config: Arc<ServerConfig>,
+    socket: &TcpStream,
+) -> Result<(TlsReader, TlsWriter), String> {
+    let connection = match ServerConnection::new(config) {
+        Ok(connection) => Arc::new(Mutex::new(connection)),
+        Err(e) => Err(e.to_string())?,
+    };
+    let (read_socket, write_socket) = match (socket.try_clone(), socket.try_clone()) {
+        (Ok(read_socket), Ok(write_socket)) => (read_socket, write_socket),
+        (Err(e), _) | (_, Err(e)) => Err(e.to_string())?,
+    };
+
+    Ok((
+        TlsReader {
+            connection: connection.clone(),
+            socket: read_socket,
+        },
+        TlsWriter {
+            connection,
+            socket: write_socket,
+        },
+    ))
+}
+
+pub struct TlsReader {
+    connection: Arc<Mutex<ServerConnection>>,
+    socket: TcpStream,
+}
+
+impl Read for TlsReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.connection.lock().unwrap().reader().read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                res => return res,
+            }
+
+            let mut raw = [0u8; 4096];
+            let n = self.socket.read(&mut raw)?;
+            if n == 0 {
+                return Ok(0);
+            }
+
+            let mut connection = self.connection.lock().unwrap();
+            let mut raw = &raw[..n];
+            while !raw.is_empty() {
+                connection.read_tls(&mut raw)?;
+                let res = connection.process_new_packets();
+                // handshake messages and alerts
+                while connection.wants_write() {
+                    connection.write_tls(&mut self.socket)?;
+                }
+                if let Err(e) = res {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+                }
+            }
+        }
+    }
+}
+
+pub struct TlsWriter {
+    connection: Arc<Mutex<ServerConnection>>,
+    socket: TcpStream,
+}
+
+impl Write for TlsWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut connection = self.connection.lock().unwrap();
+        let n = connection.writer().write(buf)?;
+        while connection.wants_write() {
+            connection.write_tls(&mut self.socket)?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.socket.flush()
+    }
+}
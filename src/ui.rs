@@ -1,52 +1,273 @@
-use crate::client_state::ClientManager;
-use crate::ui::Redraw::ClientState;
+use crate::alerts::{Alert, Alerts};
+use crate::client_messages::{LogLevel, CAP_LOG};
+use crate::client_state::{
+    ClientManager, ClientState, ClientStateChange, CommandState, Device, SessionId, SessionInfo,
+};
+use crate::conf_store::ConfStore;
+use crate::connection::ConnectionRegistry;
+use crate::logging::{LogBuffer, LogRecord};
+use crate::registry::{DeviceRegistry, KnownDevice};
+use crate::tcp_json::ListenReport;
+use chrono::{DateTime, Local};
+use std::cmp::Ordering;
+use std::io::{self, Read};
 use std::sync::mpsc;
-use std::{io, thread};
+use std::thread;
+use std::time::Duration;
 use termion::event::Key;
 use termion::input::TermRead;
 use termion::raw::IntoRawMode;
 use termion::screen::AlternateScreen;
-use tui::backend::TermionBackend;
-use tui::layout::{Constraint, Layout};
-use tui::widgets::{Block, Borders, Row, Table};
-use tui::Terminal;
+use tracing::Level;
+use tui::backend::{Backend, TermionBackend};
+use tui::layout::{Constraint, Direction, Layout};
+use tui::style::{Color, Modifier, Style};
+use tui::text::{Span, Spans};
+use tui::widgets::{Block, Borders, Paragraph, Row, Sparkline, Table, TableState};
+use tui::{Frame, Terminal};
+
+// lines of the log shown below the clients
+const LOG_LINES: usize = 10;
+
+// battery level below which a device that is not charging is shown by the low battery filter
+const LOW_BATTERY_LEVEL: f64 = 0.2;
+
+// alerts shown at once, the oldest come first
+const ALERT_LINES: usize = 5;
+
+const SNOOZE: Duration = Duration::from_secs(5 * 60);
+
+// offline devices are listed after the connected ones
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum RowKey {
+    Session(SessionId),
+    Offline(String),
+}
 
 enum Redraw {
     Key(Key),
     ClientState,
+    Tick,
+    // the terminal is gone, e.g. an attached one was closed
+    InputClosed,
 }
 
-pub fn run(client_manager: ClientManager) {
+// what the ui shows and changes, every terminal gets a clone
+#[derive(Clone)]
+pub struct ServerHandles {
+    pub client_manager: ClientManager,
+    pub connections: ConnectionRegistry,
+    pub listen_report: ListenReport,
+    pub conf_store: ConfStore,
+    pub log_buffer: LogBuffer,
+    pub alerts: Alerts,
+    pub registry: DeviceRegistry,
+}
+
+// the terminal of the server itself, q stops the server
+pub fn run(server: ServerHandles) {
     let stdout = io::stdout().into_raw_mode().unwrap();
     let stdout = AlternateScreen::from(stdout);
-    let backend = TermionBackend::new(stdout);
-    let mut terminal = Terminal::new(backend).unwrap();
+    run_with(io::stdin(), TermionBackend::new(stdout), server);
+}
+
+// returns on q or once the input is closed, the backend is dropped afterwards
+pub fn run_with<R: Read + Send + 'static, B: Backend>(input: R, backend: B, server: ServerHandles) {
+    let ServerHandles {
+        mut client_manager,
+        connections,
+        listen_report,
+        conf_store,
+        log_buffer,
+        alerts,
+        registry,
+    } = server;
+    let mut terminal = match Terminal::new(backend) {
+        Ok(terminal) => terminal,
+        Err(_) => return,
+    };
 
     let (tx, rx) = mpsc::channel();
 
+    // the threads end once the ui is gone and they have something to send
     let key_tx = tx.clone();
     thread::spawn(move || {
-        let stdin = io::stdin();
-        for c in stdin.keys() {
-            key_tx.send(Redraw::Key(c.unwrap())).unwrap();
+        for key in input.keys() {
+            let sent = match key {
+                Ok(key) => key_tx.send(Redraw::Key(key)),
+                Err(_) => break,
+            };
+            if sent.is_err() {
+                return;
+            }
         }
+        let _ = key_tx.send(Redraw::InputClosed);
     });
 
     let client_tx = tx.clone();
     let client_state_change = client_manager.get_change_receiver();
+    thread::spawn(move || {
+        while client_state_change.recv().is_ok() {
+            if client_tx.send(Redraw::ClientState).is_err() {
+                return;
+            }
+        }
+    });
+
+    // keep the last ping column up to date
+    let tick_tx = tx.clone();
     thread::spawn(move || loop {
-        client_state_change.recv().unwrap();
-        client_tx.send(ClientState).unwrap();
+        thread::sleep(Duration::from_secs(1));
+        if tick_tx.send(Redraw::Tick).is_err() {
+            return;
+        }
     });
 
-    let mut rows = vec![vec![]];
+    let mut table_view = TableView::default();
+    // the pane that / and esc apply to
+    let mut focus = Focus::Clients;
+    // the cursor stays on its row when rows change
+    let mut selected: Option<RowKey> = None;
+    let mut table_state = TableState::default();
+    let mut edit: Option<Edit> = None;
+    // row shown in the detail view instead of the tables
+    let mut detail_row: Option<RowKey> = None;
+    let mut log_view = LogView::default();
+    // addresses that could not be bound are worth a look, but the others still work
+    let mut status: Option<String> = listen_report
+        .failed
+        .first()
+        .map(|(addr, e)| format!("Could not listen on {}: {}", addr, e));
+    let title = format!(
+        "Clients (listening on {})",
+        listen_report
+            .bound
+            .iter()
+            .map(|addr| addr.to_string())
+            .collect::<Vec<String>>()
+            .join(", ")
+    );
 
     loop {
-        terminal
+        // unknown devices, the operator acts on the oldest one first
+        let pending = client_manager.get_pending_approvals();
+        let sessions = client_manager
+            .get_all_sessions()
+            .into_iter()
+            .map(|(session_id, state)| (RowKey::Session(session_id), state))
+            .collect();
+        let known_devices: Vec<(String, KnownDevice)> = registry
+            .get_devices()
+            .into_iter()
+            .filter(|(client_name, _)| client_manager.get_session_id(client_name).is_none())
+            .collect();
+        let offline = known_devices
+            .iter()
+            .map(|(client_name, known)| (RowKey::Offline(client_name.clone()), known.state.clone()))
+            .collect();
+        let mut table_rows = table_view.apply(sessions);
+        table_rows.extend(table_view.apply(offline));
+        let rows: Vec<Vec<String>> = table_rows
+            .iter()
+            .map(|(row_key, state)| match row_key {
+                RowKey::Session(session_id) => {
+                    let session = client_manager.get_session_info(*session_id);
+                    let device = session
+                        .as_ref()
+                        .and_then(|session| session.device.as_ref())
+                        .and_then(|device| client_manager.get_device(device));
+                    client_row(state, session, device)
+                }
+                RowKey::Offline(client_name) => {
+                    let known = known_devices
+                        .iter()
+                        .find(|(name, _)| name == client_name)
+                        .map(|(_, known)| known);
+                    offline_row(state, known, client_manager.get_device(client_name))
+                }
+            })
+            .collect();
+        let row_keys: Vec<RowKey> = table_rows
+            .iter()
+            .map(|(row_key, _)| row_key.clone())
+            .collect();
+        let visible_alerts: Vec<Alert> = alerts
+            .get_alerts()
+            .into_iter()
+            .filter(|alert| !alert.is_snoozed())
+            .collect();
+        // x and z act on the oldest alert that was not acknowledged yet
+        let target_alert = visible_alerts
+            .iter()
+            .position(|alert| !alert.acknowledged)
+            .or((!visible_alerts.is_empty()).then_some(0));
+        let alerting: Vec<bool> = table_rows
+            .iter()
+            .map(|(_, state)| {
+                visible_alerts.iter().any(|alert| {
+                    !alert.acknowledged && state.client_name.as_ref() == Some(&alert.client_name)
+                })
+            })
+            .collect();
+        // a device that comes online keeps the cursor and its details
+        for row in [&mut selected, &mut detail_row] {
+            if let Some(RowKey::Offline(client_name)) = row {
+                if let Some(session_id) = client_manager.get_session_id(client_name) {
+                    *row = Some(RowKey::Session(session_id));
+                }
+            }
+        }
+        if !selected
+            .as_ref()
+            .is_some_and(|selected| row_keys.contains(selected))
+        {
+            selected = row_keys.first().cloned();
+        }
+        log_view.update(&if log_view.dumps {
+            log_buffer.get_dump_records()
+        } else {
+            log_buffer.get_records()
+        });
+        // the value being edited takes the place of the status
+        let status_line = match &edit {
+            Some(edit) => Some(format!("{}: {}_", edit.field.label(), edit.input)),
+            None => status.clone(),
+        };
+        let detail = detail_row.as_ref().and_then(|row_key| {
+            Detail::new(
+                &mut client_manager,
+                &connections,
+                &conf_store,
+                &log_buffer,
+                &registry,
+                row_key,
+            )
+        });
+        if detail.is_none() {
+            detail_row = None;
+        }
+
+        // fails once an attached terminal went away
+        if terminal
             .draw(|f| {
-                let chunks = Layout::default()
-                    .constraints([Constraint::Percentage(100)].as_ref())
-                    .split(f.size());
+                if let Some(detail) = &detail {
+                    draw_detail(f, detail, status_line.as_deref());
+                    return;
+                }
+
+                let mut constraints = vec![Constraint::Min(0)];
+                if !pending.is_empty() {
+                    constraints.push(Constraint::Length(pending.len() as u16 + 3));
+                }
+                if !visible_alerts.is_empty() {
+                    let lines = visible_alerts.len().min(ALERT_LINES);
+                    constraints.push(Constraint::Length(lines as u16 + 2));
+                }
+                constraints.push(Constraint::Length(LOG_LINES as u16 + 2));
+                if status_line.is_some() {
+                    constraints.push(Constraint::Length(1));
+                }
+                let chunks = Layout::default().constraints(constraints).split(f.size());
 
                 let table = Table::new(
                     [
@@ -57,114 +278,1246 @@ pub fn run(client_manager: ClientManager) {
                         "Port In (Repair)",
                         "Port Out (Repair)",
                         "Last ping",
+                        "Battery (Remaining)",
                     ]
                     .iter(),
-                    rows.iter().map(|i| Row::Data(i.iter())),
+                    rows.iter().zip(alerting.iter()).zip(row_keys.iter()).map(
+                        |((i, alerting), row_key)| {
+                            if *alerting {
+                                Row::StyledData(i.iter(), Style::default().fg(Color::Red))
+                            } else if let RowKey::Offline(_) = row_key {
+                                Row::StyledData(i.iter(), Style::default().fg(Color::DarkGray))
+                            } else {
+                                Row::StyledData(i.iter(), Style::default())
+                            }
+                        },
+                    ),
                 )
                 .block(
                     Block::default()
-                        .title("Connected Clients")
-                        .borders(Borders::ALL),
+                        .title(format!("{}{}", title, table_view.title()))
+                        .borders(Borders::ALL)
+                        .border_style(focus_style(focus == Focus::Clients)),
                 )
+                .highlight_symbol("> ")
+                .highlight_style(Style::default().add_modifier(Modifier::REVERSED))
                 .widths(&[
-                    Constraint::Percentage(14),
-                    Constraint::Percentage(14),
-                    Constraint::Percentage(14),
-                    Constraint::Percentage(14),
-                    Constraint::Percentage(14),
-                    Constraint::Percentage(14),
-                    Constraint::Percentage(14),
+                    Constraint::Percentage(12),
+                    Constraint::Percentage(12),
+                    Constraint::Percentage(12),
+                    Constraint::Percentage(12),
+                    Constraint::Percentage(12),
+                    Constraint::Percentage(12),
+                    Constraint::Percentage(12),
+                    Constraint::Percentage(16),
                 ]);
-                f.render_widget(table, chunks[0]);
+                table_state.select(
+                    selected
+                        .as_ref()
+                        .and_then(|selected| row_keys.iter().position(|key| key == selected)),
+                );
+                f.render_stateful_widget(table, chunks[0], &mut table_state);
+
+                if !pending.is_empty() {
+                    let pending_rows: Vec<Vec<String>> = pending
+                        .iter()
+                        .enumerate()
+                        .map(|(n, (session_id, pending_approval))| {
+                            vec![
+                                format!(
+                                    "{}{}{}",
+                                    if n == 0 { "> " } else { "  " },
+                                    pending_approval.client_name,
+                                    if pending_approval.pairing {
+                                        " (known, not paired)"
+                                    } else {
+                                        ""
+                                    }
+                                ),
+                                match connections.get_info(*session_id) {
+                                    Some(connection) => connection.peer_addr.to_string(),
+                                    None => "-".to_string(),
+                                },
+                                format!("{}s", pending_approval.since.elapsed().as_secs()),
+                            ]
+                        })
+                        .collect();
+                    let pending_table = Table::new(
+                        ["Device", "Address", "Waiting"].iter(),
+                        pending_rows.iter().map(|i| Row::Data(i.iter())),
+                    )
+                    .block(
+                        Block::default()
+                            .title("Pending approval (a: approve, r: reject, b: reject and block)")
+                            .borders(Borders::ALL),
+                    )
+                    .widths(&[
+                        Constraint::Percentage(50),
+                        Constraint::Percentage(35),
+                        Constraint::Percentage(15),
+                    ]);
+                    f.render_widget(pending_table, chunks[1]);
+                }
+
+                let alerts_chunk = if pending.is_empty() { 1 } else { 2 };
+                if !visible_alerts.is_empty() {
+                    let lines: Vec<Spans> = visible_alerts
+                        .iter()
+                        .take(ALERT_LINES)
+                        .enumerate()
+                        .map(|(n, alert)| {
+                            let line = format!(
+                                "{}{} {} {}: {}",
+                                if Some(n) == target_alert { "> " } else { "  " },
+                                DateTime::<Local>::from(alert.since).format("%H:%M:%S"),
+                                alert.rule,
+                                alert.client_name,
+                                alert.message
+                            );
+                            let style = if alert.acknowledged {
+                                Style::default()
+                            } else {
+                                Style::default().fg(Color::Red)
+                            };
+                            Spans::from(Span::styled(line, style))
+                        })
+                        .collect();
+                    let paragraph = Paragraph::new(lines).block(
+                        Block::default()
+                            .title(format!(
+                                "Alerts ({}) (x: acknowledge, z: snooze {} min)",
+                                visible_alerts.len(),
+                                SNOOZE.as_secs() / 60
+                            ))
+                            .borders(Borders::ALL),
+                    );
+                    f.render_widget(paragraph, chunks[alerts_chunk]);
+                }
+
+                let log_chunk = chunks[if visible_alerts.is_empty() {
+                    alerts_chunk
+                } else {
+                    alerts_chunk + 1
+                }];
+                log_view.height = log_chunk.height.saturating_sub(2) as usize;
+                let log = Paragraph::new(
+                    log_view
+                        .visible()
+                        .iter()
+                        .map(|line| Spans::from(line.as_str()))
+                        .collect::<Vec<Spans>>(),
+                )
+                .block(
+                    Block::default()
+                        .title(log_view.title())
+                        .borders(Borders::ALL)
+                        .border_style(focus_style(focus == Focus::Log)),
+                );
+                f.render_widget(log, log_chunk);
+
+                if let Some(status) = &status_line {
+                    let paragraph = Paragraph::new(Spans::from(status.as_str()));
+                    f.render_widget(paragraph, chunks[chunks.len() - 1]);
+                }
             })
-            .unwrap();
+            .is_err()
+        {
+            break;
+        }
 
         if let Ok(input) = rx.recv() {
             match input {
+                Redraw::Key(key) if edit.is_some() => match key {
+                    Key::Char('\n') => {
+                        let res = edit.take().unwrap().apply(&mut client_manager);
+                        status = res.err();
+                    }
+                    Key::Esc => edit = None,
+                    Key::Backspace => {
+                        edit.as_mut().unwrap().input.pop();
+                    }
+                    Key::Char(c) => edit.as_mut().unwrap().input.push(c),
+                    _ => {}
+                },
+                Redraw::Key(key) if log_view.editing => log_view.edit_search(key),
+                Redraw::Key(key) if table_view.editing => table_view.edit_search(key),
+                Redraw::Key(Key::Char('\n')) | Redraw::Key(Key::Esc) if detail.is_some() => {
+                    detail_row = None;
+                }
+                Redraw::Key(Key::Char('\n')) => detail_row = selected.clone(),
                 Redraw::Key(Key::Char('q')) => {
                     break;
                 }
-                Redraw::ClientState => {
-                    rows.clear();
-                    rows.extend(client_manager.get_all_clients().iter().map(|i| {
-                        vec![
-                            // Name
-                            match &i.display_name {
-                                Some(display_name) => display_name.to_string(),
-                                None => "Not reported".to_string(),
-                            },
-                            // Power (Last update)
-                            match i.is_charging {
-                                Some(true) => "External (-)".to_string(),
-                                Some(false) => match i.battery_level {
-                                    Some(level) => format!("{:1}% (-)", level * 100.0),
-                                    None => "Internal (-)".to_string(),
-                                },
-                                None => "Not reported".to_string(),
-                            },
-                            // Audio In
-                            match i.send_audio {
-                                Some(true) => match i.send_mute {
-                                    Some(true) => "YES (muted)".to_string(),
-                                    Some(false) => "YES (not muted)".to_string(),
-                                    None => "YES (muted?)".to_string(),
-                                },
-                                Some(false) => match i.send_mute {
-                                    Some(true) => "NO (muted)".to_string(),
-                                    Some(false) => "NO (not muted)".to_string(),
-                                    None => "NO (muted?)".to_string(),
-                                },
-                                None => match i.send_mute {
-                                    Some(true) => "? (muted)".to_string(),
-                                    Some(false) => "? (not muted)".to_string(),
-                                    None => "? (muted?)".to_string(),
-                                },
-                            },
-                            // Audio Out
-                            match i.recv_audio {
-                                Some(true) => match i.recv_mute {
-                                    Some(true) => "YES (muted)".to_string(),
-                                    Some(false) => "YES (not muted)".to_string(),
-                                    None => "YES (muted?)".to_string(),
-                                },
-                                Some(false) => match i.recv_mute {
-                                    Some(true) => "NO (muted)".to_string(),
-                                    Some(false) => "NO (not muted)".to_string(),
-                                    None => "NO (muted?)".to_string(),
-                                },
-                                None => match i.recv_mute {
-                                    Some(true) => "? (muted)".to_string(),
-                                    Some(false) => "? (not muted)".to_string(),
-                                    None => "? (muted?)".to_string(),
-                                },
-                            },
-                            // Port In (Repair)
-                            format!(
-                                "{} ({})",
-                                i.send_audio_port
-                                    .map(|f| f.to_string())
-                                    .unwrap_or("?".to_string()),
-                                i.send_repair_port
-                                    .map(|f| f.to_string())
-                                    .unwrap_or("?".to_string())
+                Redraw::Key(Key::Char(key @ 'x')) | Redraw::Key(Key::Char(key @ 'z')) => {
+                    if let Some(alert) = target_alert.map(|n| &visible_alerts[n]) {
+                        if key == 'x' {
+                            alerts.acknowledge(&alert.rule, &alert.client_name);
+                        } else {
+                            alerts.snooze(&alert.rule, &alert.client_name, SNOOZE);
+                        }
+                    }
+                }
+                Redraw::Key(Key::Char(key @ 'a'))
+                | Redraw::Key(Key::Char(key @ 'r'))
+                | Redraw::Key(Key::Char(key @ 'b')) => {
+                    if let Some((session_id, pending_approval)) = pending.first().cloned() {
+                        let client_name = pending_approval.client_name.clone();
+                        let res = match key {
+                            'a' => conf_store.approve_device(
+                                &mut client_manager,
+                                session_id,
+                                pending_approval,
                             ),
-                            // Port Out (Repair)
-                            format!(
-                                "{} ({})",
-                                i.recv_audio_port
-                                    .map(|f| f.to_string())
-                                    .unwrap_or("?".to_string()),
-                                i.recv_repair_port
-                                    .map(|f| f.to_string())
-                                    .unwrap_or("?".to_string())
+                            _ => conf_store.reject_device(
+                                &mut client_manager,
+                                session_id,
+                                pending_approval,
+                                key == 'b',
                             ),
-                            // Last Ping
-                            "-".to_string(),
-                        ]
-                    }));
+                        };
+                        status = match res {
+                            Ok(_) => None,
+                            Err(e) => Some(format!("{}: {}", client_name, e)),
+                        };
+                    }
+                }
+                Redraw::Key(Key::Up) | Redraw::Key(Key::Char('k')) => {
+                    let i = selected
+                        .as_ref()
+                        .and_then(|selected| row_keys.iter().position(|key| key == selected));
+                    selected = match i {
+                        Some(i) => row_keys.get(i.saturating_sub(1)).cloned(),
+                        None => row_keys.last().cloned(),
+                    };
+                }
+                Redraw::Key(Key::Down) | Redraw::Key(Key::Char('j')) => {
+                    let i = selected
+                        .as_ref()
+                        .and_then(|selected| row_keys.iter().position(|key| key == selected));
+                    selected = match i {
+                        Some(i) => row_keys.get((i + 1).min(row_keys.len() - 1)).cloned(),
+                        None => row_keys.first().cloned(),
+                    };
+                }
+                Redraw::Key(Key::Char(key @ 'i'))
+                | Redraw::Key(Key::Char(key @ 'o'))
+                | Redraw::Key(Key::Char(key @ 'I'))
+                | Redraw::Key(Key::Char(key @ 'O')) => {
+                    status = selected_session(&selected)
+                        .and_then(|session_id| toggle(&mut client_manager, session_id, key))
+                        .err();
+                }
+                Redraw::Key(Key::Char(key @ 'n'))
+                | Redraw::Key(Key::Char(key @ 's'))
+                | Redraw::Key(Key::Char(key @ 't')) => {
+                    let field = match key {
+                        'n' => EditField::DisplayName,
+                        's' => EditField::Ports,
+                        _ => EditField::BatteryLogInterval,
+                    };
+                    match selected_session(&selected)
+                        .and_then(|session_id| Edit::new(&mut client_manager, session_id, field))
+                    {
+                        Ok(new_edit) => edit = Some(new_edit),
+                        Err(e) => status = Some(e),
+                    }
+                }
+                Redraw::Key(Key::PageUp) => log_view.scroll_up(),
+                Redraw::Key(Key::PageDown) => log_view.scroll_down(),
+                Redraw::Key(Key::Home) => log_view.top = Some(0),
+                Redraw::Key(Key::End) => log_view.top = None,
+                Redraw::Key(Key::Char('p')) => log_view.toggle_pause(),
+                Redraw::Key(Key::Char('l')) => log_view.next_level(),
+                Redraw::Key(Key::Char('c')) => log_view.next_client(),
+                Redraw::Key(Key::Char('\t')) => {
+                    focus = match focus {
+                        Focus::Clients => Focus::Log,
+                        Focus::Log => Focus::Clients,
+                    };
+                }
+                Redraw::Key(Key::Char('/')) => match focus {
+                    Focus::Clients => table_view.editing = true,
+                    Focus::Log => log_view.editing = true,
+                },
+                Redraw::Key(Key::Esc) => match focus {
+                    Focus::Clients => table_view.search.clear(),
+                    Focus::Log => log_view.search.clear(),
+                },
+                Redraw::Key(Key::Char('S')) => table_view.next_sort(),
+                Redraw::Key(Key::Char('f')) => table_view.next_filter(),
+                Redraw::Key(Key::Char('D')) => {
+                    log_view.dumps = !log_view.dumps;
+                    log_view.top = None;
+                }
+                // the selected client is asked, its dumped log is shown right away
+                Redraw::Key(Key::Char('d')) => {
+                    let res = selected_session(&selected)
+                        .and_then(|session_id| request_log_dump(&mut client_manager, session_id));
+                    status = Some(match res {
+                        Ok(client_name) => {
+                            let status = format!("Requested the log of {}", client_name);
+                            log_view.client_filter = Some(client_name);
+                            log_view.dumps = true;
+                            log_view.top = None;
+                            status
+                        }
+                        Err(e) => e,
+                    });
+                }
+                Redraw::Key(Key::Char('v')) => {
+                    let res = selected_session(&selected).and_then(|session_id| {
+                        next_client_log_level(&mut client_manager, session_id)
+                    });
+                    status = Some(match res {
+                        Ok(status) => status,
+                        Err(e) => e,
+                    });
+                }
+                Redraw::InputClosed => break,
+                Redraw::ClientState | Redraw::Tick | Redraw::Key(_) => {}
+            }
+        }
+    }
+}
+
+// i and o mute and unmute audio in and out, I and O switch it on and off
+// offline devices can not be changed
+fn selected_session(selected: &Option<RowKey>) -> Result<SessionId, String> {
+    match selected {
+        Some(RowKey::Session(session_id)) => Ok(*session_id),
+        Some(RowKey::Offline(client_name)) => Err(format!("{} is offline", client_name)),
+        None => Err("No client selected".to_string()),
+    }
+}
+
+fn toggle(
+    client_manager: &mut ClientManager,
+    session_id: SessionId,
+    key: char,
+) -> Result<(), String> {
+    let state = logged_in_client(client_manager, session_id)?;
+    // the client only gets the command once both values are known. an unknown mute is assumed
+    // on, so that toggling one side never unmutes the other and an unknown side becomes muted.
+    // setting the other value to what it is already does not change anything.
+    let changes = match key {
+        'i' => [
+            ClientStateChange::RecvMute(state.recv_mute.unwrap_or(true)),
+            ClientStateChange::SendMute(!state.send_mute.unwrap_or(false)),
+        ],
+        'o' => [
+            ClientStateChange::SendMute(state.send_mute.unwrap_or(true)),
+            ClientStateChange::RecvMute(!state.recv_mute.unwrap_or(false)),
+        ],
+        'I' => [
+            ClientStateChange::RecvAudio(state.recv_audio.unwrap_or(false)),
+            ClientStateChange::SendAudio(!state.send_audio.unwrap_or(false)),
+        ],
+        _ => [
+            ClientStateChange::SendAudio(state.send_audio.unwrap_or(false)),
+            ClientStateChange::RecvAudio(!state.recv_audio.unwrap_or(false)),
+        ],
+    };
+    for change in changes {
+        client_manager.set_client_property(session_id, change)?;
+    }
+    Ok(())
+}
+
+// changes are persisted in the config of the client, which needs its name
+fn logged_in_client(
+    client_manager: &mut ClientManager,
+    session_id: SessionId,
+) -> Result<ClientState, String> {
+    let state = client_manager.get_client(session_id)?;
+    if state.client_name.is_none() {
+        Err("The client is not logged in")?
+    }
+    Ok(state)
+}
+
+// returns the client name
+fn log_client(client_manager: &mut ClientManager, session_id: SessionId) -> Result<String, String> {
+    let client_name = logged_in_client(client_manager, session_id)?
+        .client_name
+        .unwrap_or_default();
+    if !client_manager.has_capability(session_id, CAP_LOG) {
+        Err(format!("{} does not support remote logging", client_name))?
+    }
+    Ok(client_name)
+}
+
+// returns the client name
+fn request_log_dump(
+    client_manager: &mut ClientManager,
+    session_id: SessionId,
+) -> Result<String, String> {
+    let client_name = log_client(client_manager, session_id)?;
+    client_manager.request_log_dump(session_id);
+    Ok(client_name)
+}
+
+// none is the default of the client, then from quiet to verbose
+fn next_client_log_level(
+    client_manager: &mut ClientManager,
+    session_id: SessionId,
+) -> Result<String, String> {
+    let client_name = log_client(client_manager, session_id)?;
+    let log_level = match client_manager.get_client(session_id)?.log_level {
+        None => Some(LogLevel::Error),
+        Some(LogLevel::Error) => Some(LogLevel::Warn),
+        Some(LogLevel::Warn) => Some(LogLevel::Info),
+        Some(LogLevel::Info) => Some(LogLevel::Debug),
+        Some(LogLevel::Debug) => Some(LogLevel::Trace),
+        Some(LogLevel::Trace) => None,
+    };
+    client_manager.set_client_property(session_id, ClientStateChange::LogLevel(log_level))?;
+    Ok(match log_level {
+        Some(log_level) => format!("Log level of {}: {:?}", client_name, log_level),
+        None => format!("Log level of {}: default", client_name),
+    })
+}
+
+// everything known about one session, for the detail view
+struct Detail {
+    title: String,
+    state: Vec<String>,
+    session: Vec<String>,
+    history: Vec<String>,
+    // battery levels in percent, oldest first
+    battery: Vec<u64>,
+    battery_title: String,
+    log: Vec<String>,
+}
+
+impl Detail {
+    // none once the session is gone
+    fn new(
+        client_manager: &mut ClientManager,
+        connections: &ConnectionRegistry,
+        conf_store: &ConfStore,
+        log_buffer: &LogBuffer,
+        registry: &DeviceRegistry,
+        row_key: &RowKey,
+    ) -> Option<Detail> {
+        let (state, session_id) = match row_key {
+            RowKey::Session(session_id) => (
+                client_manager.get_client(*session_id).ok()?,
+                Some(*session_id),
+            ),
+            RowKey::Offline(client_name) => (registry.get_device(client_name)?.state, None),
+        };
+        let client_name = state.client_name.clone();
+
+        // all fields, including ones added later
+        let state_lines = match serde_json::to_value(&state) {
+            Ok(serde_json::Value::Object(fields)) => fields
+                .iter()
+                .map(|(key, value)| format!("{}: {}", key, value))
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        let mut session = match session_id {
+            Some(session_id) => session_lines(client_manager, connections, session_id)?,
+            None => vec!["Offline".to_string()],
+        };
+        // kept across sessions and restarts
+        if let Some(known) = client_name
+            .as_ref()
+            .and_then(|client_name| registry.get_device(client_name))
+        {
+            if let Some(last_seen) = known.last_seen {
+                let last_seen: DateTime<Local> = last_seen.into();
+                session.push(format!(
+                    "Last seen: {}",
+                    last_seen.format("%Y-%m-%d %H:%M:%S")
+                ));
+            }
+            if let Some(last_addr) = known.last_addr {
+                session.push(format!("Last address: {}", last_addr));
+            }
+            session.push(format!(
+                "Connected in total: {}",
+                format_duration(known.connected_total())
+            ));
+        }
+        if let Some(client_name) = &client_name {
+            session.push(format!(
+                "Config: {}",
+                conf_store.client_file(client_name, "json").display()
+            ));
+        }
+
+        let device = client_name
+            .as_ref()
+            .and_then(|client_name| client_manager.get_device(client_name))
+            .unwrap_or_default();
+        let history = device
+            .history
+            .iter()
+            .map(|(time, change)| {
+                let time: DateTime<Local> = (*time).into();
+                let change = match change {
+                    ClientStateChange::Add => "Connected".to_string(),
+                    ClientStateChange::Remove(_) => "Disconnected".to_string(),
+                    change => format!("{:?}", change),
+                };
+                format!("{} {}", time.format("%H:%M:%S"), change)
+            })
+            .collect();
+        let battery = device
+            .battery
+            .iter()
+            .map(|sample| (sample.level.clamp(0.0, 1.0) * 100.0).round() as u64)
+            .collect();
+        let battery_title = match (device.battery.back(), device.battery_remaining()) {
+            (Some(sample), _) if sample.is_charging => "Battery (charging)".to_string(),
+            (Some(_), Some(remaining)) => {
+                format!("Battery ({} remaining)", format_duration(remaining))
+            }
+            _ => "Battery".to_string(),
+        };
+
+        let log = log_buffer
+            .get_records()
+            .iter()
+            .filter(|record| {
+                (session_id.is_some() && record.session == session_id.map(|id| id.to_string()))
+                    || (client_name.is_some() && record.client_name == client_name)
+            })
+            .map(|record| format!("{} {}", record.time.format("%H:%M:%S"), record))
+            .collect();
+
+        Some(Detail {
+            title: format!(
+                "{} (Enter or Esc: back)",
+                client_name
+                    .or(session_id.map(|session_id| session_id.to_string()))
+                    .unwrap_or_default()
+            ),
+            state: state_lines,
+            session,
+            history,
+            battery,
+            battery_title,
+            log,
+        })
+    }
+}
+
+// none once the session is gone
+fn session_lines(
+    client_manager: &ClientManager,
+    connections: &ConnectionRegistry,
+    session_id: SessionId,
+) -> Option<Vec<String>> {
+    let session_info = client_manager.get_session_info(session_id)?;
+    let mut session = vec![format!("Session: {}", session_id)];
+    match connections.get_info(session_id) {
+        Some(connection) => {
+            let connected_at: DateTime<Local> = connection.connected_at.into();
+            session.push(format!("Peer: {}", connection.peer_addr));
+            session.push(format!(
+                "Connected: {}",
+                connected_at.format("%Y-%m-%d %H:%M:%S")
+            ));
+            session.push(format!("Encrypted: {}", connection.encrypted));
+            session.push(format!(
+                "Bytes in/out: {}/{}",
+                connection.bytes_in, connection.bytes_out
+            ));
+        }
+        None => session.push("Not connected".to_string()),
+    }
+    session.push(format!(
+        "Last ping: {}s ago",
+        session_info.last_seen.elapsed().as_secs()
+    ));
+    session.push(format!(
+        "Protocol: {} ({})",
+        session_info
+            .protocol_version
+            .map(|version| version.to_string())
+            .unwrap_or("?".to_string()),
+        session_info.capabilities.join(", ")
+    ));
+    let mut commands: Vec<_> = session_info.commands.iter().collect();
+    commands.sort_by_key(|(message_type, _)| **message_type);
+    for (message_type, command) in commands {
+        session.push(format!(
+            "{} #{}: {:?}",
+            message_type, command.id, command.state
+        ));
+    }
+    Some(session)
+}
+
+fn draw_detail<B: Backend>(f: &mut Frame<B>, detail: &Detail, status: Option<&str>) {
+    let mut constraints = vec![
+        Constraint::Length(detail.state.len().max(detail.session.len()) as u16 + 2),
+        Constraint::Length(6),
+        Constraint::Percentage(40),
+        Constraint::Min(0),
+    ];
+    if status.is_some() {
+        constraints.push(Constraint::Length(1));
+    }
+    let chunks = Layout::default().constraints(constraints).split(f.size());
+    let top = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Percentage(50), Constraint::Percentage(50)])
+        .split(chunks[0]);
+
+    f.render_widget(
+        last_lines(&detail.title, &detail.state, top[0].height),
+        top[0],
+    );
+    f.render_widget(
+        last_lines("Session", &detail.session, top[1].height),
+        top[1],
+    );
+    // the newest reports that fit
+    let skip = detail
+        .battery
+        .len()
+        .saturating_sub(chunks[1].width.saturating_sub(2) as usize);
+    let battery = Sparkline::default()
+        .block(
+            Block::default()
+                .title(detail.battery_title.as_str())
+                .borders(Borders::ALL),
+        )
+        .data(&detail.battery[skip..])
+        .max(100);
+    f.render_widget(battery, chunks[1]);
+    f.render_widget(
+        last_lines("State changes", &detail.history, chunks[2].height),
+        chunks[2],
+    );
+    f.render_widget(last_lines("Log", &detail.log, chunks[3].height), chunks[3]);
+
+    if let Some(status) = status {
+        let paragraph = Paragraph::new(Spans::from(status));
+        f.render_widget(paragraph, chunks[chunks.len() - 1]);
+    }
+}
+
+// the newest lines of a list that does not fit
+fn last_lines<'a>(title: &str, lines: &'a [String], height: u16) -> Paragraph<'a> {
+    let skip = lines
+        .len()
+        .saturating_sub(height.saturating_sub(2) as usize);
+    Paragraph::new(
+        lines[skip..]
+            .iter()
+            .map(|line| Spans::from(line.as_str()))
+            .collect::<Vec<Spans>>(),
+    )
+    .block(
+        Block::default()
+            .title(title.to_string())
+            .borders(Borders::ALL),
+    )
+}
+
+// battery reports shown in the table
+const SPARKLINE_LEN: usize = 12;
+
+// one character per level from empty to full
+fn sparkline(levels: impl Iterator<Item = f64>) -> String {
+    const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+    levels
+        .map(|level| BARS[((level.clamp(0.0, 1.0) * 7.0).round()) as usize])
+        .collect()
+}
+
+fn format_duration(duration: Duration) -> String {
+    let minutes = duration.as_secs() / 60;
+    format!("{}h{:02}m", minutes / 60, minutes % 60)
+}
+
+// what is known of a device that is not connected, its config and the last battery report
+fn offline_row(
+    i: &ClientState,
+    known: Option<&KnownDevice>,
+    device: Option<Device>,
+) -> Vec<String> {
+    let last_seen = known
+        .and_then(|known| known.last_seen)
+        .and_then(|last_seen| last_seen.elapsed().ok())
+        .map(|age| format!("{} ago", format_duration(age)))
+        .unwrap_or("never".to_string());
+    let last_addr = known
+        .and_then(|known| known.last_addr)
+        .map(|last_addr| format!(" from {}", last_addr))
+        .unwrap_or_default();
+
+    vec![
+        // Name
+        i.display_name
+            .clone()
+            .or(i.client_name.clone())
+            .unwrap_or_default(),
+        // Power (Last update)
+        match (i.is_charging, i.battery_level) {
+            (Some(true), _) => "External".to_string(),
+            (_, Some(level)) => format!("{:.0}%", level * 100.0),
+            _ => "-".to_string(),
+        },
+        // Audio In
+        "offline".to_string(),
+        // Audio Out
+        "-".to_string(),
+        // Port In (Repair)
+        "-".to_string(),
+        // Port Out (Repair)
+        "-".to_string(),
+        // Last ping
+        format!("{}{}", last_seen, last_addr),
+        // Battery (Remaining)
+        match device {
+            Some(device) if !device.battery.is_empty() => sparkline(
+                device
+                    .battery
+                    .iter()
+                    .skip(device.battery.len().saturating_sub(SPARKLINE_LEN))
+                    .map(|sample| sample.level),
+            ),
+            _ => "-".to_string(),
+        },
+    ]
+}
+
+fn client_row(
+    i: &ClientState,
+    session: Option<SessionInfo>,
+    device: Option<Device>,
+) -> Vec<String> {
+    // time since the last battery report
+    let battery_age = device
+        .as_ref()
+        .and_then(|device| device.battery.back())
+        .and_then(|sample| sample.time.elapsed().ok())
+        .map(|age| format!("{}s ago", age.as_secs()))
+        .unwrap_or("-".to_string());
+
+    // whether the last mute command was confirmed by the client
+    let mute_state = match session
+        .as_ref()
+        .and_then(|session| session.commands.get("MuteAudio"))
+        .map(|command| &command.state)
+    {
+        Some(CommandState::Pending) => " [pending]",
+        Some(CommandState::Nacked(_)) => " [rejected]",
+        Some(CommandState::TimedOut) => " [no ack]",
+        Some(CommandState::Acked) | None => "",
+    };
+
+    vec![
+        // Name
+        match &i.display_name {
+            Some(display_name) => display_name.to_string(),
+            None => "Not reported".to_string(),
+        },
+        // Power (Last update)
+        match i.is_charging {
+            Some(true) => format!("External ({})", battery_age),
+            Some(false) => match i.battery_level {
+                Some(level) => format!("{:.0}% ({})", level * 100.0, battery_age),
+                None => format!("Internal ({})", battery_age),
+            },
+            None => "Not reported".to_string(),
+        },
+        // Audio In
+        format!(
+            "{}{}",
+            match i.send_audio {
+                Some(true) => match i.send_mute {
+                    Some(true) => "YES (muted)".to_string(),
+                    Some(false) => "YES (not muted)".to_string(),
+                    None => "YES (muted?)".to_string(),
+                },
+                Some(false) => match i.send_mute {
+                    Some(true) => "NO (muted)".to_string(),
+                    Some(false) => "NO (not muted)".to_string(),
+                    None => "NO (muted?)".to_string(),
+                },
+                None => match i.send_mute {
+                    Some(true) => "? (muted)".to_string(),
+                    Some(false) => "? (not muted)".to_string(),
+                    None => "? (muted?)".to_string(),
+                },
+            },
+            mute_state
+        ),
+        // Audio Out
+        format!(
+            "{}{}",
+            match i.recv_audio {
+                Some(true) => match i.recv_mute {
+                    Some(true) => "YES (muted)".to_string(),
+                    Some(false) => "YES (not muted)".to_string(),
+                    None => "YES (muted?)".to_string(),
+                },
+                Some(false) => match i.recv_mute {
+                    Some(true) => "NO (muted)".to_string(),
+                    Some(false) => "NO (not muted)".to_string(),
+                    None => "NO (muted?)".to_string(),
+                },
+                None => match i.recv_mute {
+                    Some(true) => "? (muted)".to_string(),
+                    Some(false) => "? (not muted)".to_string(),
+                    None => "? (muted?)".to_string(),
+                },
+            },
+            mute_state
+        ),
+        // Port In (Repair)
+        format!(
+            "{} ({})",
+            i.send_audio_port
+                .map(|f| f.to_string())
+                .unwrap_or("?".to_string()),
+            i.send_repair_port
+                .map(|f| f.to_string())
+                .unwrap_or("?".to_string())
+        ),
+        // Port Out (Repair)
+        format!(
+            "{} ({})",
+            i.recv_audio_port
+                .map(|f| f.to_string())
+                .unwrap_or("?".to_string()),
+            i.recv_repair_port
+                .map(|f| f.to_string())
+                .unwrap_or("?".to_string())
+        ),
+        // Last Ping
+        match &session {
+            Some(session) => format!("{}s ago", session.last_seen.elapsed().as_secs()),
+            None => "-".to_string(),
+        },
+        // Battery (Remaining)
+        match &device {
+            Some(device) if !device.battery.is_empty() => format!(
+                "{} ({})",
+                sparkline(
+                    device
+                        .battery
+                        .iter()
+                        .skip(device.battery.len().saturating_sub(SPARKLINE_LEN))
+                        .map(|sample| sample.level)
+                ),
+                match device.battery_remaining() {
+                    Some(remaining) => format_duration(remaining),
+                    None => "-".to_string(),
+                }
+            ),
+            _ => "-".to_string(),
+        },
+    ]
+}
+
+// what the log pane shows, it follows new records unless it was scrolled or paused
+#[derive(Default)]
+struct LogView {
+    // dumped log lines instead of the live log
+    dumps: bool,
+    // first shown line, none while following the end of the log
+    top: Option<usize>,
+    // least severe level shown
+    min_level: Option<Level>,
+    client_filter: Option<String>,
+    search: String,
+    // the search is being typed
+    editing: bool,
+    // lines passing the filters, updated from the log buffer
+    lines: Vec<String>,
+    // client names seen in the log, to cycle through
+    clients: Vec<String>,
+    // set when drawing, used for paging
+    height: usize,
+}
+
+impl LogView {
+    fn update(&mut self, records: &[LogRecord]) {
+        self.clients = records
+            .iter()
+            .filter_map(|record| record.client_name.clone())
+            .collect();
+        self.clients.sort();
+        self.clients.dedup();
+
+        let search = self.search.to_lowercase();
+        self.lines = records
+            .iter()
+            .filter(|record| self.min_level.is_none_or(|level| record.level <= level))
+            .filter(|record| match &self.client_filter {
+                Some(client_name) => record.client_name.as_ref() == Some(client_name),
+                None => true,
+            })
+            .map(|record| format!("{} {}", record.time.format("%H:%M:%S"), record))
+            .filter(|line| search.is_empty() || line.to_lowercase().contains(&search))
+            .collect();
+    }
+
+    fn last_top(&self) -> usize {
+        self.lines.len().saturating_sub(self.height)
+    }
+
+    fn visible(&self) -> &[String] {
+        let top = self
+            .top
+            .unwrap_or_else(|| self.last_top())
+            .min(self.last_top());
+        let end = (top + self.height).min(self.lines.len());
+        &self.lines[top..end]
+    }
+
+    fn title(&self) -> String {
+        let mut title = if self.dumps {
+            "Log dumps".to_string()
+        } else {
+            "Log".to_string()
+        };
+        if let Some(level) = self.min_level {
+            title.push_str(&format!(" [level: {}+]", level));
+        }
+        if let Some(client_name) = &self.client_filter {
+            title.push_str(&format!(" [client: {}]", client_name));
+        }
+        if self.editing {
+            title.push_str(&format!(" [search: {}_]", self.search));
+        } else if !self.search.is_empty() {
+            title.push_str(&format!(" [search: {}]", self.search));
+        }
+        if self.top.is_some() {
+            title.push_str(" [paused]");
+        }
+        title.push_str(
+            " (p: pause, l: level, c: client, /: search, D: live/dumps, d: request dump, \
+             v: client verbosity)",
+        );
+        title
+    }
+
+    // scrolling up stops following new records
+    fn scroll_up(&mut self) {
+        let top = self
+            .top
+            .unwrap_or_else(|| self.last_top())
+            .min(self.last_top());
+        self.top = Some(top.saturating_sub(self.height.max(1)));
+    }
+
+    // follows new records again once the end is reached
+    fn scroll_down(&mut self) {
+        if let Some(top) = self.top {
+            let top = top + self.height.max(1);
+            self.top = if top >= self.last_top() {
+                None
+            } else {
+                Some(top)
+            };
+        }
+    }
+
+    fn toggle_pause(&mut self) {
+        self.top = match self.top {
+            Some(_) => None,
+            None => Some(self.last_top()),
+        };
+    }
+
+    // all levels, then info and more severe, warn and error, only error
+    fn next_level(&mut self) {
+        self.min_level = match self.min_level {
+            None => Some(Level::INFO),
+            Some(Level::INFO) => Some(Level::WARN),
+            Some(Level::WARN) => Some(Level::ERROR),
+            Some(_) => None,
+        };
+        self.top = None;
+    }
+
+    // all clients, then each client seen in the log
+    fn next_client(&mut self) {
+        self.client_filter = match &self.client_filter {
+            None => self.clients.first().cloned(),
+            Some(client_name) => self
+                .clients
+                .iter()
+                .find(|name| *name > client_name)
+                .cloned(),
+        };
+        self.top = None;
+    }
+
+    fn edit_search(&mut self, key: Key) {
+        self.editing = edit_search(&mut self.search, key);
+        self.top = None;
+    }
+}
+
+// applies a key to a search being typed, returns whether it is still being typed
+fn edit_search(search: &mut String, key: Key) -> bool {
+    match key {
+        Key::Char('\n') => return false,
+        Key::Esc => {
+            search.clear();
+            return false;
+        }
+        Key::Backspace => {
+            search.pop();
+        }
+        Key::Char(c) => search.push(c),
+        _ => {}
+    }
+    true
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Focus {
+    Clients,
+    Log,
+}
+
+fn focus_style(focused: bool) -> Style {
+    if focused {
+        Style::default().fg(Color::Yellow)
+    } else {
+        Style::default()
+    }
+}
+
+#[derive(Clone, Copy, Default, PartialEq)]
+enum SortKey {
+    // sessions are numbered in the order they connected
+    #[default]
+    ConnectTime,
+    DisplayName,
+    // lowest first, so that devices that need a charge are on top
+    Battery,
+    // muted first
+    Mute,
+}
+
+#[derive(Clone, Copy, Default, PartialEq)]
+enum QuickFilter {
+    #[default]
+    All,
+    Muted,
+    LowBattery,
+}
+
+// order and filters of the client table
+#[derive(Default)]
+struct TableView {
+    sort: SortKey,
+    filter: QuickFilter,
+    // matches display name or client name
+    search: String,
+    // the search is being typed
+    editing: bool,
+}
+
+impl TableView {
+    // rows with the same sort key stay in the order they connected, offline devices by name
+    fn apply<K: Ord>(&self, mut sessions: Vec<(K, ClientState)>) -> Vec<(K, ClientState)> {
+        let search = self.search.to_lowercase();
+        sessions.retain(|(_, state)| {
+            let matches_filter = match self.filter {
+                QuickFilter::All => true,
+                QuickFilter::Muted => is_muted(state),
+                QuickFilter::LowBattery => has_low_battery(state),
+            };
+            let matches_search = search.is_empty()
+                || [&state.display_name, &state.client_name]
+                    .iter()
+                    .filter_map(|name| name.as_ref())
+                    .any(|name| name.to_lowercase().contains(&search));
+            matches_filter && matches_search
+        });
+
+        sessions.sort_by(|(a, _), (b, _)| a.cmp(b));
+        match self.sort {
+            SortKey::ConnectTime => {}
+            // unnamed devices last
+            SortKey::DisplayName => sessions.sort_by_key(|(_, state)| {
+                match state.display_name.as_ref().or(state.client_name.as_ref()) {
+                    Some(name) => (false, name.to_lowercase()),
+                    None => (true, String::new()),
+                }
+            }),
+            // unknown levels last
+            SortKey::Battery => {
+                sessions.sort_by(|(_, a), (_, b)| match (a.battery_level, b.battery_level) {
+                    (Some(a), Some(b)) => a.total_cmp(&b),
+                    (Some(_), None) => Ordering::Less,
+                    (None, Some(_)) => Ordering::Greater,
+                    (None, None) => Ordering::Equal,
+                })
+            }
+            SortKey::Mute => sessions.sort_by_key(|(_, state)| !is_muted(state)),
+        }
+        sessions
+    }
+
+    fn title(&self) -> String {
+        let mut title = format!(
+            " [sort: {}]",
+            match self.sort {
+                SortKey::ConnectTime => "connect time",
+                SortKey::DisplayName => "name",
+                SortKey::Battery => "battery",
+                SortKey::Mute => "mute",
+            }
+        );
+        match self.filter {
+            QuickFilter::All => {}
+            QuickFilter::Muted => title.push_str(" [only muted]"),
+            QuickFilter::LowBattery => title.push_str(" [low battery]"),
+        }
+        if self.editing {
+            title.push_str(&format!(" [search: {}_]", self.search));
+        } else if !self.search.is_empty() {
+            title.push_str(&format!(" [search: {}]", self.search));
+        }
+        title.push_str(" (Tab: focus, S: sort, f: filter, /: search)");
+        title
+    }
+
+    fn next_sort(&mut self) {
+        self.sort = match self.sort {
+            SortKey::ConnectTime => SortKey::DisplayName,
+            SortKey::DisplayName => SortKey::Battery,
+            SortKey::Battery => SortKey::Mute,
+            SortKey::Mute => SortKey::ConnectTime,
+        };
+    }
+
+    fn next_filter(&mut self) {
+        self.filter = match self.filter {
+            QuickFilter::All => QuickFilter::Muted,
+            QuickFilter::Muted => QuickFilter::LowBattery,
+            QuickFilter::LowBattery => QuickFilter::All,
+        };
+    }
+
+    fn edit_search(&mut self, key: Key) {
+        self.editing = edit_search(&mut self.search, key);
+    }
+}
+
+fn is_muted(state: &ClientState) -> bool {
+    state.send_mute == Some(true) || state.recv_mute == Some(true)
+}
+
+// devices that are charging are fine
+fn has_low_battery(state: &ClientState) -> bool {
+    state.is_charging != Some(true)
+        && state
+            .battery_level
+            .is_some_and(|level| level < LOW_BATTERY_LEVEL)
+}
+
+#[derive(Clone, Copy)]
+enum EditField {
+    DisplayName,
+    // audio in, audio in repair, audio out, audio out repair as in the table
+    Ports,
+    BatteryLogInterval,
+}
+
+impl EditField {
+    fn label(&self) -> &'static str {
+        match self {
+            EditField::DisplayName => "Display name",
+            EditField::Ports => "Ports (in, in repair, out, out repair)",
+            EditField::BatteryLogInterval => "Battery log interval in seconds (empty for none)",
+        }
+    }
+}
+
+// a value of a client being typed in the status line, enter applies it and esc cancels
+struct Edit {
+    session_id: SessionId,
+    field: EditField,
+    input: String,
+}
+
+impl Edit {
+    // starts out with the current value
+    fn new(
+        client_manager: &mut ClientManager,
+        session_id: SessionId,
+        field: EditField,
+    ) -> Result<Edit, String> {
+        let state = logged_in_client(client_manager, session_id)?;
+        let input = match field {
+            EditField::DisplayName => state.display_name.unwrap_or_default(),
+            EditField::Ports => [
+                state.send_audio_port,
+                state.send_repair_port,
+                state.recv_audio_port,
+                state.recv_repair_port,
+            ]
+            .iter()
+            .map(|port| port.map(|port| port.to_string()).unwrap_or_default())
+            .collect::<Vec<String>>()
+            .join(" ")
+            .trim()
+            .to_string(),
+            EditField::BatteryLogInterval => state
+                .battery_log_interval_secs
+                .map(|secs| secs.to_string())
+                .unwrap_or_default(),
+        };
+        Ok(Edit {
+            session_id,
+            field,
+            input,
+        })
+    }
+
+    fn apply(self, client_manager: &mut ClientManager) -> Result<(), String> {
+        let input = self.input.trim();
+        let changes = match self.field {
+            EditField::DisplayName => {
+                if input.is_empty() {
+                    Err("The display name can not be empty")?
+                }
+                vec![ClientStateChange::DisplayName(input.to_string())]
+            }
+            EditField::Ports => {
+                let ports = input
+                    .split(|c: char| c == ',' || c.is_whitespace())
+                    .filter(|port| !port.is_empty())
+                    .map(|port| port.parse::<u16>())
+                    .collect::<Result<Vec<u16>, _>>();
+                match ports.as_deref() {
+                    Ok([send_audio, send_repair, recv_audio, recv_repair]) => vec![
+                        ClientStateChange::SendAudioPort(*send_audio),
+                        ClientStateChange::SendRepairPort(*send_repair),
+                        ClientStateChange::RecvAudioPort(*recv_audio),
+                        ClientStateChange::RecvRepairPort(*recv_repair),
+                    ],
+                    _ => Err("Four ports are needed")?,
                 }
-                _ => {}
             }
+            EditField::BatteryLogInterval => match input {
+                "" => vec![ClientStateChange::BatteryLogIntervalSecs(None)],
+                _ => match input.parse::<u32>() {
+                    Ok(secs) if secs > 0 => {
+                        vec![ClientStateChange::BatteryLogIntervalSecs(Some(secs))]
+                    }
+                    _ => Err("Invalid interval")?,
+                },
+            },
+        };
+        for change in changes {
+            client_manager.set_client_property(self.session_id, change)?;
         }
+        Ok(())
     }
 }
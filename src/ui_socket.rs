@@ -0,0 +1,201 @@
+// attaches the terminal ui to a headless server through a unix socket. the ui runs in the server,
+// the attaching terminal only passes its keys in and the screen out, like dtach does.
+// the terminal sends its size as "<cols> <rows>\n" first.
+use crate::ui::{self, ServerHandles};
+use std::fs::{self, Permissions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::Shutdown;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::thread;
+use termion::raw::IntoRawMode;
+use termion::screen::{ToAlternateScreen, ToMainScreen};
+use tracing::{info, warn};
+use tui::backend::{Backend, TermionBackend};
+use tui::buffer::Cell;
+use tui::layout::Rect;
+
+// a socket left over from a server that did not stop cleanly is replaced, one of a running
+// server is not. only the user running the server may attach.
+pub fn listen(path: &Path) -> Result<UnixListener, String> {
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            Err(format!("{}: a server is running already", path.display()))?
+        }
+        if let Err(e) = fs::remove_file(path) {
+            Err(format!("{}: {}", path.display(), e))?
+        }
+    }
+    let listener = match UnixListener::bind(path) {
+        Ok(listener) => listener,
+        Err(e) => Err(format!("{}: {}", path.display(), e))?,
+    };
+    if let Err(e) = fs::set_permissions(path, Permissions::from_mode(0o600)) {
+        Err(format!("{}: {}", path.display(), e))?
+    }
+    Ok(listener)
+}
+
+// every attached terminal gets its own ui
+pub fn serve(listener: UnixListener, server: ServerHandles) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!(error = %e, "Could not accept a terminal");
+                continue;
+            }
+        };
+        let server = server.clone();
+        thread::spawn(move || {
+            if let Err(e) = run_attached(stream, server) {
+                warn!(error = %e, "Could not attach a terminal");
+            }
+        });
+    }
+}
+
+fn run_attached(mut stream: UnixStream, server: ServerHandles) -> Result<(), String> {
+    let mut input = match stream.try_clone() {
+        Ok(input) => BufReader::new(input),
+        Err(e) => Err(e.to_string())?,
+    };
+    let mut line = String::new();
+    if let Err(e) = input.read_line(&mut line) {
+        Err(e.to_string())?
+    }
+    let size: Vec<u16> = line
+        .split_whitespace()
+        .filter_map(|n| n.parse().ok())
+        .collect();
+    let size = match size[..] {
+        [cols, rows] if cols > 0 && rows > 0 => Rect::new(0, 0, cols, rows),
+        _ => Err("Invalid terminal size")?,
+    };
+    let mut output = match stream.try_clone() {
+        Ok(output) => output,
+        Err(e) => Err(e.to_string())?,
+    };
+
+    info!("Terminal attached");
+    // not termion's AlternateScreen, that panics if the terminal is gone when it is dropped
+    if let Err(e) = write!(output, "{}", ToAlternateScreen) {
+        Err(e.to_string())?
+    }
+    let backend = FixedSizeBackend {
+        backend: TermionBackend::new(output),
+        size,
+    };
+    ui::run_with(input, backend, server);
+    // closing the socket ends the attaching process
+    let _ = write!(stream, "{}", ToMainScreen);
+    let _ = stream.shutdown(Shutdown::Both);
+    info!("Terminal detached");
+    Ok(())
+}
+
+// the size of the attached terminal instead of the one of the server, which has none
+struct FixedSizeBackend<B: Backend> {
+    backend: B,
+    size: Rect,
+}
+
+impl<B: Backend> Backend for FixedSizeBackend<B> {
+    fn draw<'a, I>(&mut self, content: I) -> io::Result<()>
+    where
+        I: Iterator<Item = (u16, u16, &'a Cell)>,
+    {
+        self.backend.draw(content)
+    }
+
+    fn hide_cursor(&mut self) -> io::Result<()> {
+        self.backend.hide_cursor()
+    }
+
+    // also called when the ui is dropped, which prints failures to stderr. a terminal that is
+    // gone already ended the ui by failing to draw.
+    fn show_cursor(&mut self) -> io::Result<()> {
+        let _ = self.backend.show_cursor();
+        Ok(())
+    }
+
+    fn get_cursor(&mut self) -> io::Result<(u16, u16)> {
+        self.backend.get_cursor()
+    }
+
+    fn set_cursor(&mut self, x: u16, y: u16) -> io::Result<()> {
+        self.backend.set_cursor(x, y)
+    }
+
+    fn clear(&mut self) -> io::Result<()> {
+        self.backend.clear()
+    }
+
+    fn size(&self) -> io::Result<Rect> {
+        Ok(self.size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.backend.flush()
+    }
+}
+
+// runs in the attaching process until the ui is left with q or the server stops
+pub fn attach(path: &Path) -> Result<(), String> {
+    let mut stream = match UnixStream::connect(path) {
+        Ok(stream) => stream,
+        Err(e) => Err(format!("{}: {}", path.display(), e))?,
+    };
+    let (cols, rows) = match termion::terminal_size() {
+        Ok(size) => size,
+        Err(e) => Err(format!("No terminal: {}", e))?,
+    };
+    if let Err(e) = writeln!(stream, "{} {}", cols, rows) {
+        Err(e.to_string())?
+    }
+
+    // keys are passed on as they are typed, the raw mode ends when stdout is dropped
+    let mut stdout = match io::stdout().into_raw_mode() {
+        Ok(stdout) => stdout,
+        Err(e) => Err(e.to_string())?,
+    };
+    let mut input = match stream.try_clone() {
+        Ok(input) => input,
+        Err(e) => Err(e.to_string())?,
+    };
+    thread::spawn(move || io::copy(&mut io::stdin(), &mut input));
+    let res = io::copy(&mut stream, &mut stdout);
+    // the server could not restore the screen if it stopped
+    let _ = write!(stdout, "{}{}", ToMainScreen, termion::cursor::Show);
+    let _ = stdout.flush();
+    match res {
+        Ok(_) => Ok(()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    #[test]
+    fn listen_replaces_stale_socket() {
+        let path = std::env::temp_dir().join(format!("gecko_ui_socket_{}.sock", process::id()));
+        let _ = fs::remove_file(&path);
+
+        let listener = listen(&path).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        // the server is still running
+        assert!(listen(&path).is_err());
+
+        // the file stays behind, e.g. after the server was killed
+        drop(listener);
+        let listener = listen(&path).unwrap();
+        assert!(UnixStream::connect(&path).is_ok());
+        drop(listener);
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,607 @@
+// json notifications about server events posted to http endpoints, e.g. a team chat
+use crate::client_state::{ClientManager, ClientStateChange, SessionId};
+use chrono::Local;
+use rustls::{ClientConfig, ClientConnection, RootCertStore, ServerName, StreamOwned};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tracing::{debug, warn};
+
+// events waiting for an endpoint, newer ones are dropped while it is down
+const QUEUE_LEN: usize = 64;
+const ATTEMPTS: u32 = 5;
+// doubled after every failed attempt
+const FIRST_RETRY: Duration = Duration::from_secs(1);
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+// ca bundles of the common distributions and mac
+const CA_FILES: [&str; 3] = [
+    "/etc/ssl/certs/ca-certificates.crt",
+    "/etc/pki/tls/certs/ca-bundle.crt",
+    "/etc/ssl/cert.pem",
+];
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    pub url: String,
+    #[serde(default = "all_events")]
+    pub events: Vec<EventKind>,
+    // battery_low is sent when a client drops below this level
+    #[serde(default = "default_battery_percent")]
+    pub battery_percent: f64,
+}
+
+impl WebhookConfig {
+    // all events
+    pub fn new(url: String) -> Self {
+        WebhookConfig {
+            url,
+            events: all_events(),
+            battery_percent: default_battery_percent(),
+        }
+    }
+}
+
+fn all_events() -> Vec<EventKind> {
+    vec![
+        EventKind::Connect,
+        EventKind::Disconnect,
+        EventKind::BatteryLow,
+        EventKind::ConfigRejected,
+        EventKind::Alert,
+    ]
+}
+
+fn default_battery_percent() -> f64 {
+    20.0
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    Connect,
+    Disconnect,
+    BatteryLow,
+    ConfigRejected,
+    Alert,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    Connect {
+        session: String,
+        client_name: String,
+    },
+    Disconnect {
+        session: String,
+        client_name: String,
+    },
+    BatteryLow {
+        session: String,
+        client_name: String,
+        percent: f64,
+        // each endpoint has its own threshold
+        #[serde(skip)]
+        previous_percent: Option<f64>,
+    },
+    // a config file in the config dir that was ignored
+    ConfigRejected {
+        path: String,
+        error: String,
+    },
+    Alert {
+        rule: String,
+        client_name: String,
+        message: String,
+    },
+}
+
+impl Event {
+    fn kind(&self) -> EventKind {
+        match self {
+            Event::Connect { .. } => EventKind::Connect,
+            Event::Disconnect { .. } => EventKind::Disconnect,
+            Event::BatteryLow { .. } => EventKind::BatteryLow,
+            Event::ConfigRejected { .. } => EventKind::ConfigRejected,
+            Event::Alert { .. } => EventKind::Alert,
+        }
+    }
+}
+
+// the text of the payload, chat services show it as the message
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::Connect { client_name, .. } => write!(f, "{} connected", client_name),
+            Event::Disconnect { client_name, .. } => write!(f, "{} disconnected", client_name),
+            Event::BatteryLow {
+                client_name,
+                percent,
+                ..
+            } => write!(f, "{} battery at {:.0}%", client_name, percent),
+            Event::ConfigRejected { path, error } => {
+                write!(f, "Config {} rejected: {}", path, error)
+            }
+            Event::Alert {
+                rule,
+                client_name,
+                message,
+            } => write!(f, "Alert {} for {}: {}", rule, client_name, message),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Payload<'a> {
+    #[serde(flatten)]
+    event: &'a Event,
+    time: String,
+    text: String,
+}
+
+struct Hook {
+    url: String,
+    events: Vec<EventKind>,
+    battery_percent: f64,
+    tx: SyncSender<Event>,
+}
+
+impl Hook {
+    fn wants(&self, event: &Event) -> bool {
+        if !self.events.contains(&event.kind()) {
+            return false;
+        }
+        match event {
+            Event::BatteryLow {
+                percent,
+                previous_percent,
+                ..
+            } => {
+                *percent < self.battery_percent
+                    && previous_percent.is_none_or(|previous| previous >= self.battery_percent)
+            }
+            _ => true,
+        }
+    }
+}
+
+// every endpoint has its own delivery thread, so a slow one does not hold up the others
+#[derive(Clone, Default)]
+pub struct Webhooks {
+    hooks: Arc<Vec<Hook>>,
+}
+
+impl Webhooks {
+    // fails on invalid urls or if https is used without ca certificates
+    pub fn new(configs: &[WebhookConfig]) -> Result<Self, String> {
+        let mut tls_config = None;
+        let mut hooks = Vec::new();
+        for config in configs {
+            let url = Url::parse(&config.url)?;
+            let tls = if url.https {
+                if tls_config.is_none() {
+                    tls_config = Some(client_config()?);
+                }
+                tls_config.clone()
+            } else {
+                None
+            };
+            let (tx, rx) = sync_channel(QUEUE_LEN);
+            thread::spawn(move || deliver(url, tls, rx));
+            hooks.push(Hook {
+                url: config.url.clone(),
+                events: config.events.clone(),
+                battery_percent: config.battery_percent,
+                tx,
+            });
+        }
+        Ok(Webhooks {
+            hooks: Arc::new(hooks),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+
+    pub fn notify(&self, event: Event) {
+        for hook in self.hooks.iter().filter(|hook| hook.wants(&event)) {
+            match hook.tx.try_send(event.clone()) {
+                Ok(_) => {}
+                Err(TrySendError::Full(_)) => {
+                    warn!(url = %hook.url, "Webhook is behind, dropping event");
+                }
+                Err(TrySendError::Disconnected(_)) => {}
+            }
+        }
+    }
+}
+
+// turns the changes of the clients into events
+pub fn run(webhooks: Webhooks, client_manager: ClientManager) {
+    let change_receiver = client_manager.get_change_receiver();
+    let mut client_names: HashMap<SessionId, String> = HashMap::new();
+    let mut battery_percent: HashMap<SessionId, f64> = HashMap::new();
+    // devices announced as connected. a device that logs in again kicks its old session or keeps
+    // both, depending on the duplicate policy, it is only connected once and disconnected once
+    // its last session is gone.
+    let mut connected: HashSet<String> = HashSet::new();
+
+    loop {
+        let (session_id, state_change) = match change_receiver.recv() {
+            Ok(change) => change,
+            Err(_) => return,
+        };
+        match state_change {
+            ClientStateChange::ClientName(client_name) => {
+                // the name is set again when the config of the client changes
+                let is_new = client_names
+                    .insert(session_id, client_name.clone())
+                    .is_none();
+                if is_new && connected.insert(client_name.clone()) {
+                    webhooks.notify(Event::Connect {
+                        session: session_id.to_string(),
+                        client_name,
+                    });
+                }
+            }
+            ClientStateChange::Remove(_) => {
+                battery_percent.remove(&session_id);
+                if let Some(client_name) = client_names.remove(&session_id) {
+                    // the removed session is only dropped from its device after this change
+                    let has_other_session = client_manager
+                        .get_session_ids(&client_name)
+                        .iter()
+                        .any(|id| *id != session_id);
+                    if !has_other_session && connected.remove(&client_name) {
+                        webhooks.notify(Event::Disconnect {
+                            session: session_id.to_string(),
+                            client_name,
+                        });
+                    }
+                }
+            }
+            ClientStateChange::BatteryLevel(level) => {
+                let percent = level * 100.0;
+                let previous_percent = battery_percent.insert(session_id, percent);
+                if let Some(client_name) = client_names.get(&session_id) {
+                    webhooks.notify(Event::BatteryLow {
+                        session: session_id.to_string(),
+                        client_name: client_name.clone(),
+                        percent,
+                        previous_percent,
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn deliver(url: Url, tls: Option<Arc<ClientConfig>>, rx: Receiver<Event>) {
+    for event in rx {
+        let payload = Payload {
+            event: &event,
+            time: Local::now().to_rfc3339(),
+            text: event.to_string(),
+        };
+        let body = serde_json::to_string(&payload).unwrap();
+
+        let mut retry = FIRST_RETRY;
+        for attempt in 1..=ATTEMPTS {
+            match post(&url, tls.clone(), &body) {
+                Ok(_) => {
+                    debug!(url = %url.raw, text = %payload.text, "Webhook delivered");
+                    break;
+                }
+                Err(e) if attempt < ATTEMPTS => {
+                    debug!(url = %url.raw, error = %e, attempt, "Webhook failed, retrying");
+                    thread::sleep(retry);
+                    retry *= 2;
+                }
+                Err(e) => {
+                    warn!(url = %url.raw, error = %e, text = %payload.text, "Webhook failed");
+                }
+            }
+        }
+    }
+}
+
+// a plain http/1.1 post, the endpoint closes the connection after its answer
+fn post(url: &Url, tls: Option<Arc<ClientConfig>>, body: &str) -> Result<(), String> {
+    let addrs = match (url.host.as_str(), url.port).to_socket_addrs() {
+        Ok(addrs) => addrs,
+        Err(e) => Err(e.to_string())?,
+    };
+    let mut socket = Err("No address found".to_string());
+    for addr in addrs {
+        socket = TcpStream::connect_timeout(&addr, TIMEOUT).map_err(|e| e.to_string());
+        if socket.is_ok() {
+            break;
+        }
+    }
+    let socket = socket?;
+    if let Err(e) = socket
+        .set_read_timeout(Some(TIMEOUT))
+        .and(socket.set_write_timeout(Some(TIMEOUT)))
+    {
+        Err(e.to_string())?
+    }
+
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: gecko_audio_ctrl\r\n\
+         Content-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        url.path,
+        url.authority,
+        body.len(),
+        body
+    );
+    let status_line = match tls {
+        Some(config) => {
+            let server_name = match ServerName::try_from(url.host.as_str()) {
+                Ok(server_name) => server_name,
+                Err(e) => Err(e.to_string())?,
+            };
+            let connection = match ClientConnection::new(config, server_name) {
+                Ok(connection) => connection,
+                Err(e) => Err(e.to_string())?,
+            };
+            exchange(StreamOwned::new(connection, socket), &request)?
+        }
+        None => exchange(socket, &request)?,
+    };
+
+    // e.g. "HTTP/1.1 204 No Content"
+    match status_line.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        Some(status) => Err(format!("Status {}", status)),
+        None => Err("No http response".to_string()),
+    }
+}
+
+// sends the request and returns the status line of the response
+fn exchange<S: std::io::Read + Write>(mut stream: S, request: &str) -> Result<String, String> {
+    if let Err(e) = stream
+        .write_all(request.as_bytes())
+        .and_then(|_| stream.flush())
+    {
+        Err(e.to_string())?
+    }
+    let mut status_line = String::new();
+    match BufReader::new(stream).read_line(&mut status_line) {
+        Ok(_) => Ok(status_line),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn client_config() -> Result<Arc<ClientConfig>, String> {
+    let mut roots = RootCertStore::empty();
+    for path in CA_FILES.iter() {
+        if let Ok(file) = File::open(path) {
+            if let Ok(certs) = rustls_pemfile::certs(&mut BufReader::new(file)) {
+                roots.add_parsable_certificates(&certs);
+            }
+        }
+    }
+    if roots.is_empty() {
+        Err(format!(
+            "No ca certificates found in {}",
+            CA_FILES.join(", ")
+        ))?
+    }
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+struct Url {
+    raw: String,
+    https: bool,
+    // host and port as given, for the host header
+    authority: String,
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl Url {
+    // http://host[:port][/path][?query], ipv6 addresses in brackets
+    fn parse(raw: &str) -> Result<Url, String> {
+        let (https, rest) = if let Some(rest) = raw.strip_prefix("https://") {
+            (true, rest)
+        } else if let Some(rest) = raw.strip_prefix("http://") {
+            (false, rest)
+        } else {
+            Err(format!("{}: only http and https urls are supported", raw))?
+        };
+        let (authority, path) = match rest.find(['/', '?']) {
+            Some(i) if rest[i..].starts_with('?') => (&rest[..i], format!("/{}", &rest[i..])),
+            Some(i) => (&rest[..i], rest[i..].to_string()),
+            None => (rest, "/".to_string()),
+        };
+        // the colon of an ipv6 address is no port separator
+        let (host, port) = match authority.rfind(':') {
+            Some(i) if !authority[i..].contains(']') => {
+                (&authority[..i], Some(&authority[i + 1..]))
+            }
+            _ => (authority, None),
+        };
+        let port = match port {
+            Some(port) => match port.parse() {
+                Ok(port) => port,
+                Err(_) => Err(format!("{}: invalid port", raw))?,
+            },
+            None if https => 443,
+            None => 80,
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            Err(format!("{}: no host", raw))?
+        }
+        Ok(Url {
+            raw: raw.to_string(),
+            https,
+            authority: authority.to_string(),
+            host: host.to_string(),
+            port,
+            path,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::thread::JoinHandle;
+
+    // answers the requests with the given statuses in turn and returns them
+    fn endpoint(statuses: &'static [&'static str]) -> (u16, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = thread::spawn(move || {
+            let mut requests = Vec::new();
+            for status in statuses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut request = String::new();
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if let Some(value) = line.strip_prefix("Content-Length: ") {
+                        content_length = value.trim().parse().unwrap();
+                    }
+                    request.push_str(&line);
+                    if line == "\r\n" {
+                        break;
+                    }
+                }
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+                request.push_str(&String::from_utf8(body).unwrap());
+                let response = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status);
+                reader.get_mut().write_all(response.as_bytes()).unwrap();
+                requests.push(request);
+            }
+            requests
+        });
+        (port, handle)
+    }
+
+    fn body(request: &str) -> serde_json::Value {
+        let (_, body) = request.split_once("\r\n\r\n").unwrap();
+        serde_json::from_str(body).unwrap()
+    }
+
+    #[test]
+    fn post_request() {
+        let (port, handle) = endpoint(&["204 No Content"]);
+        let url = Url::parse(&format!("http://127.0.0.1:{}/hook?x=1", port)).unwrap();
+        let body = r#"{"event":"connect"}"#;
+        post(&url, None, body).unwrap();
+
+        let requests = handle.join().unwrap();
+        let request = &requests[0];
+        assert!(request.starts_with("POST /hook?x=1 HTTP/1.1\r\n"));
+        assert!(request.contains(&format!("\r\nHost: 127.0.0.1:{}\r\n", port)));
+        assert!(request.contains(&format!("\r\nContent-Length: {}\r\n", body.len())));
+        assert!(request.contains("\r\nContent-Type: application/json\r\n"));
+        assert!(request.ends_with(&format!("\r\n\r\n{}", body)));
+    }
+
+    #[test]
+    fn post_fails_on_error_status() {
+        let (port, handle) = endpoint(&["500 Internal Server Error"]);
+        let url = Url::parse(&format!("http://127.0.0.1:{}", port)).unwrap();
+        assert_eq!(post(&url, None, "{}"), Err("Status 500".to_string()));
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn deliver_retries() {
+        let (port, handle) = endpoint(&["500 Internal Server Error", "200 OK"]);
+        let url = Url::parse(&format!("http://127.0.0.1:{}", port)).unwrap();
+        let (tx, rx) = sync_channel(QUEUE_LEN);
+        tx.send(Event::Connect {
+            session: "1".to_string(),
+            client_name: "kitchen".to_string(),
+        })
+        .unwrap();
+        drop(tx);
+        // returns once the queue is empty
+        deliver(url, None, rx);
+
+        let requests = handle.join().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0], requests[1]);
+        let body = body(&requests[1]);
+        assert_eq!(body["event"], "connect");
+        assert_eq!(body["session"], "1");
+        assert_eq!(body["client_name"], "kitchen");
+        assert_eq!(body["text"], "kitchen connected");
+        assert!(body["time"].is_string());
+    }
+
+    #[test]
+    fn parse_url() {
+        let url = Url::parse("http://example.com").unwrap();
+        assert!(!url.https);
+        assert_eq!(url.authority, "example.com");
+        assert_eq!(url.host, "example.com");
+        assert_eq!(url.port, 80);
+        assert_eq!(url.path, "/");
+
+        let url = Url::parse("https://example.com/a/b?c=d").unwrap();
+        assert!(url.https);
+        assert_eq!(url.port, 443);
+        assert_eq!(url.path, "/a/b?c=d");
+
+        let url = Url::parse("http://host:8080?x=1").unwrap();
+        assert_eq!(url.authority, "host:8080");
+        assert_eq!(url.host, "host");
+        assert_eq!(url.port, 8080);
+        assert_eq!(url.path, "/?x=1");
+
+        let url = Url::parse("http://host?x=/y").unwrap();
+        assert_eq!(url.host, "host");
+        assert_eq!(url.path, "/?x=/y");
+    }
+
+    #[test]
+    fn parse_ipv6_url() {
+        let url = Url::parse("http://[::1]:8080/hook").unwrap();
+        assert_eq!(url.authority, "[::1]:8080");
+        assert_eq!(url.host, "::1");
+        assert_eq!(url.port, 8080);
+        assert_eq!(url.path, "/hook");
+
+        let url = Url::parse("https://[fe80::1]").unwrap();
+        assert_eq!(url.host, "fe80::1");
+        assert_eq!(url.port, 443);
+        assert_eq!(url.path, "/");
+    }
+
+    #[test]
+    fn parse_invalid_url() {
+        assert!(Url::parse("ftp://example.com").is_err());
+        assert!(Url::parse("example.com").is_err());
+        assert!(Url::parse("http://").is_err());
+        assert!(Url::parse("http://:8080").is_err());
+        assert!(Url::parse("http://host:port").is_err());
+        assert!(Url::parse("http://host:70000").is_err());
+    }
+}
@@ -1,15 +1,35 @@
 // announce tcp_json address via dns-sd
 use astro_dnssd::register::DNSServiceBuilder;
+use astro_dnssd::txt::TXTRecord;
 use std::thread;
+use tracing::{error, info};
 
-pub fn start(port: u16) {
+// txt records are used to announce e.g. the tls port and certificate fingerprint
+pub fn start(name: String, port: u16, txt: Vec<(String, String)>) {
     thread::spawn(move || {
-        let mut service = DNSServiceBuilder::new("_geckoaudio._tcp")
+        let mut txt_record = TXTRecord::new();
+        for (key, value) in txt.iter() {
+            if let Err(e) = txt_record.insert(key, Some(value)) {
+                error!(key = %key, error = ?e, "Could not add txt record");
+            }
+        }
+        let service = DNSServiceBuilder::new("_geckoaudio._tcp")
             .with_port(port)
-            .with_name("Gecko Audio Streaming")
-            .build()
-            .unwrap();
-        service.register(|_reply| ()).unwrap();
+            .with_name(&name)
+            .with_txt_record(txt_record)
+            .build();
+        let mut service = match service {
+            Ok(service) => service,
+            Err(e) => {
+                error!(error = ?e, "Could not create dns-sd service, clients have to be configured manually");
+                return;
+            }
+        };
+        if let Err(e) = service.register(|_reply| ()) {
+            error!(error = ?e, "Could not announce via dns-sd, clients have to be configured manually");
+            return;
+        }
+        info!(name = %name, port, "Announced via dns-sd");
         loop {
             service.process_result();
         }